@@ -1,216 +1,70 @@
+use crate::chat_backend::get_chat_backend;
 use crate::db::{Log, LogLevels};
-use crate::globals::{self, get_magnus_id, get_open_ai_key, get_reqwest_client, get_thread_id};
-use crate::tools::*;
+use crate::error::{check_response, MagnusError};
+use crate::globals::{self, get_open_ai_key, get_reqwest_client};
+use crate::request::{get_stream_idle_timeout, get_stream_timeout_error, send_stream_with_retry};
+use crate::settings::{get_api_base_url, get_settings};
+use crate::tools::get_tool_registry;
+use crate::{Payload, APP_HANDLE};
 use cpal::SampleRate;
 use crossbeam::channel::Sender;
 use globals::get_auth_user_id;
 use ogg::reading::async_api::PacketReader;
 use opus::Decoder;
 use reqwest::header::TRANSFER_ENCODING;
-use reqwest::Error;
 use serde_json::{Map, Value};
-use std::time::Duration;
 use tokio_stream::StreamExt;
 use tokio_util::io::StreamReader;
+use tauri::Manager;
+use tokio_util::sync::CancellationToken;
+
+// returns Magnus' response to the user's message, or None if the turn was cancelled
+pub async fn run(
+    user_message: String,
+    cancel_token: CancellationToken,
+) -> Result<Option<String>, MagnusError> {
+    let result = get_chat_backend()
+        .send_message(user_message, cancel_token)
+        .await;
 
-pub async fn run(user_message: String) -> String {
-    let message = serde_json::json!({
-        "role": "user",
-        "content": user_message
-    });
-
-    let _ = create_message(message, get_thread_id()).await;
-
-    let run_id: String = create_run(get_thread_id()).await.unwrap_or_else(|err| {
-        panic!("Error occurred: {:?}", err);
-    });
-
-    let _ = run_and_wait(&run_id, get_thread_id()).await;
+    match &result {
+        Ok(_) => set_backend_availability(true),
+        Err(MagnusError::Network(_)) => set_backend_availability(false),
+        Err(_) => {}
+    }
 
-    let assistant_response = get_assistant_last_response(get_thread_id()).await.unwrap();
+    let (log_level, message) = match &result {
+        Ok(_) => (LogLevels::Info, "Successful Assistant Response!".to_string()),
+        Err(err) => (LogLevels::Error, format!("Assistant response failed: {:?}", err)),
+    };
 
     let _ = Log::log(Log {
         user_id: get_auth_user_id(),
-        log_level: LogLevels::Info,
-        message: "Successful Assistant Response!".to_string(),
+        log_level,
+        message,
         source: Some("assistant.rs".to_string()),
     })
     .await;
 
-    assistant_response
-}
-
-pub async fn create_message_thread() -> Result<String, Error> {
-    let response = get_reqwest_client()
-        .post("https://api.openai.com/v1/threads")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-        .header("OpenAI-Beta", "assistants=v2")
-        .send()
-        .await?;
-
-    let thread = response.json::<serde_json::Value>().await?;
-
-    Ok(thread["id"].to_string())
-}
-
-pub async fn create_message(
-    user_message: serde_json::Value,
-    thread_id: String,
-) -> Result<(), Error> {
-    get_reqwest_client()
-        .post(format!(
-            "https://api.openai.com/v1/threads/{}/messages",
-            thread_id
-        ))
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-        .header("OpenAI-Beta", "assistants=v2")
-        .json(&user_message)
-        .send()
-        .await?;
-
-    Ok(())
+    result
 }
 
-pub async fn create_run(thread_id: String) -> Result<String, Error> {
-    let n_messages_in_context = if globals::get_is_signed_in() { 5 } else { 2 };
-
-    let data = serde_json::json!({
-        "assistant_id": get_magnus_id(),
-        "truncation_strategy": {
-            "type": "last_messages",
-            "last_messages": n_messages_in_context
-        }
-    });
-
-    let response = get_reqwest_client()
-        .post(format!(
-            "https://api.openai.com/v1/threads/{}/runs",
-            thread_id
-        ))
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-        .header("OpenAI-Beta", "assistants=v2")
-        .json(&data)
-        .send()
-        .await?;
-
-    let run = response.json::<serde_json::Value>().await?;
-
-    Ok(run["id"].to_string().trim_matches('\"').to_string())
-}
-
-pub async fn run_and_wait(run_id: &str, thread_id: String) -> Result<(), Error> {
-    loop {
-        let response = get_reqwest_client()
-            .get(format!(
-                "https://api.openai.com/v1/threads/{}/runs/{}",
-                thread_id, run_id
-            ))
-            .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-            .header("OpenAI-Beta", "assistants=v2")
-            .send()
-            .await?;
-
-        let run = response.json::<serde_json::Value>().await?;
-
-        if run["status"] == "completed" {
-            return Ok(());
-        } else if run["status"] == "requires_action"
-            && run["required_action"]["type"] == "submit_tool_outputs"
-        {
-            let mut tool_outputs: Vec<serde_json::Value> = vec![];
-
-            if let Some(tool_calls) =
-                run["required_action"]["submit_tool_outputs"]["tool_calls"].as_array()
-            {
-                for tool_call in tool_calls {
-                    if let Some(tool_call_obj) = tool_call.as_object() {
-                        // println!("\ntool_call:\n{:#?}", tool_call);
-
-                        let tool = &tool_call_obj["function"]["name"]
-                            .to_string()
-                            .trim_matches('"')
-                            .to_string();
-
-                        let tool_output: String;
-
-                        let arguments = &tool_call_obj["function"]["arguments"].as_str().unwrap();
-
-                        let arguments_object = serde_json::from_str::<
-                            serde_json::Map<String, serde_json::Value>,
-                        >(arguments);
-
-                        match arguments_object {
-                            Ok(args) => {
-                                tool_output = execute(tool, args).await?;
-                            }
-                            Err(_) => {
-                                tool_output = "No arguments key found in tool call".to_string()
-                            }
-                        }
-
-                        // println!("received output: {}\n for tool call: {}", tool_output, tool_call["id"]);
-
-                        tool_outputs.push(serde_json::json!({
-                            "tool_call_id": tool_call["id"],
-                            "output": tool_output
-                        }));
-                    }
-                }
-                let _ = submit_tool_outputs(
-                    run_id,
-                    thread_id.clone(),
-                    serde_json::json!({"tool_outputs": tool_outputs}),
-                )
-                .await;
-            }
-        } else {
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
+// records whether the chat backend can be reached, letting the frontend know when that changes
+fn set_backend_availability(is_available: bool) {
+    if globals::get_is_backend_available() == is_available {
+        return;
+    }
+    globals::set_is_backend_available(is_available);
+
+    if let Some(app_handle) = APP_HANDLE.lock().unwrap().as_ref() {
+        let status = if is_available { "available" } else { "unavailable" };
+        let _ = app_handle.emit_all(
+            "backend-status",
+            Payload {
+                message: status.to_string(),
+            },
+        );
     }
-}
-
-pub async fn submit_tool_outputs(
-    run_id: &str,
-    thread_id: String,
-    tool_outputs: serde_json::Value,
-) -> Result<(), Error> {
-    let _ = get_reqwest_client()
-        .post(format!(
-            "https://api.openai.com/v1/threads/{}/runs/{}/submit_tool_outputs",
-            thread_id, run_id
-        ))
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-        .header("OpenAI-Beta", "assistants=v2")
-        .json(&tool_outputs)
-        .send()
-        .await;
-
-    Ok(())
-}
-
-pub async fn get_assistant_last_response(thread_id: String) -> Result<String, Error> {
-    let response = get_reqwest_client()
-        .get(format!(
-            "https://api.openai.com/v1/threads/{}/messages",
-            thread_id
-        ))
-        .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-        .header("OpenAI-Beta", "assistants=v2")
-        .send()
-        .await?;
-
-    let messages = response.json::<serde_json::Value>().await?;
-
-    let assistant_response = messages["data"][0]["content"][0]["text"]["value"]
-        .as_str()
-        .unwrap()
-        .to_string();
-
-    Ok(assistant_response)
 }
 
 pub async fn create_speech(
@@ -218,30 +72,38 @@ pub async fn create_speech(
     audio_output_sender: Sender<Vec<i16>>,
     sample_rate: SampleRate,
     channels: u16,
-) -> Result<(), Error> {
+) -> Result<(), MagnusError> {
     let channels: opus::Channels = match channels {
         1 => opus::Channels::Mono,
         2 => opus::Channels::Stereo,
-        _ => panic!(),
+        _ => {
+            return Err(MagnusError::AudioDevice(format!(
+                "speech can't be played on {} channels",
+                channels
+            )))
+        }
     };
-    let mut opus_decoder = Decoder::new(sample_rate.0, channels).unwrap();
+    let mut opus_decoder = Decoder::new(sample_rate.0, channels)
+        .map_err(|err| MagnusError::AudioDevice(format!("couldn't decode speech: {}", err)))?;
 
     let data = serde_json::json!({
-        "model": "tts-1",
+        "model": get_settings().speech_model,
         "input": assistant_message,
         "voice": "echo",
         "response_format": "opus"
     });
 
     //returns a response that contains a byte stream
-    let response = get_reqwest_client()
-        .post("https://api.openai.com/v1/audio/speech")
-        .header(TRANSFER_ENCODING, "chunked")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", get_open_ai_key()))
-        .json(&data)
-        .send()
-        .await?;
+    let response = send_stream_with_retry(
+        get_reqwest_client()
+            .post(format!("{}/audio/speech", get_api_base_url()))
+            .header(TRANSFER_ENCODING, "chunked")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+            .json(&data),
+    )
+    .await?;
+    let response = check_response(response).await?;
 
     let bytes_stream = response.bytes_stream();
     let stream = bytes_stream
@@ -249,7 +111,14 @@ pub async fn create_speech(
     let stream_reader = StreamReader::new(stream);
     let mut packet_reader = PacketReader::new(stream_reader);
 
-    while let Some(packet) = packet_reader.next().await {
+    // the body is read a packet at a time, so a server that stops sending is noticed before the next packet
+    let idle_timeout = get_stream_idle_timeout();
+    loop {
+        let packet = match tokio::time::timeout(idle_timeout, packet_reader.next()).await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break,
+            Err(_) => return Err(get_stream_timeout_error(idle_timeout)),
+        };
         match packet {
             Ok(packet) => {
                 let mut samples: Vec<i16> = vec![0; 1920];
@@ -262,7 +131,9 @@ pub async fn create_speech(
                             Ok(_) => {}
                             Err(e) => {
                                 if e.is_disconnected() {
-                                    panic!("Audio output channel disconnected!")
+                                    return Err(MagnusError::AudioDevice(
+                                        "the audio output stream closed".to_string(),
+                                    ));
                                 }
                             }
                         }
@@ -275,18 +146,11 @@ pub async fn create_speech(
     Ok(())
 }
 
-async fn execute(tool: &str, args: Map<String, Value>) -> Result<String, Error> {
+pub async fn execute(tool: &str, args: Map<String, Value>) -> Result<String, MagnusError> {
     println!("wants to use {} tool with args:\n{:#?}", tool, args);
 
-    let result = match tool {
-        "CLIPBOARD" => CLIPBOARD.execute(args).await,
-        "FORECAST" => FORECAST.execute(args).await,
-        "LOCATION_COORDINATES" => LOCATION_COORDINATES.execute(args).await,
-        "SCREENSHOT" => SCREENSHOT.execute(args).await,
-        "TIME" => TIME.execute(args).await,
-        "USER_COORDINATES" => USER_COORDINATES.execute(args).await,
-        _ => todo!(),
-    };
-
-    Ok(result)
+    match get_tool_registry().get(tool) {
+        Some(tool) => tool.execute(args).await,
+        None => Err(MagnusError::UnknownTool(tool.to_string())),
+    }
 }
@@ -0,0 +1,223 @@
+use crate::error::MagnusError;
+use crate::settings::get_settings;
+use crate::{audio_input, audio_output};
+use cpal::traits::DeviceTrait;
+use cpal::{
+    BufferSize, Device, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/*
+Streams use the device's default config unless settings.json has a preference for the device in
+"audioInputDeviceConfigs" or "audioOutputDeviceConfigs", by device name, e.g.
+{ "USB Microphone": { "sampleRate": 16000, "channels": 1, "bufferSize": 512 } }. Anything left out stays the default.
+
+A preference is checked against the configs the device supports when it's set through the app, and again every time a
+stream is opened since the device may have changed in the meantime. If the device doesn't support it anymore the
+default config is used and the reason is logged, rather than not being able to listen or speak at all.
+
+Speech is decoded from Opus straight at the output stream's sample rate and channels, and Opus only decodes to 8, 12,
+16, 24 or 48kHz on 1 or 2 channels, so output preferences are held to those as well.
+*/
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioDeviceConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buffer_size: Option<u32>, // in frames
+}
+
+impl AudioDeviceConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == AudioDeviceConfig::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioDirection {
+    Input,
+    Output,
+}
+
+pub fn find_device(device_name: &str, direction: AudioDirection) -> Option<Device> {
+    let devices = match direction {
+        AudioDirection::Input => audio_input::get_audio_input_device_list(),
+        AudioDirection::Output => audio_output::get_audio_output_device_list(),
+    };
+
+    devices
+        .into_iter()
+        .find(|device| device.name().ok().as_deref() == Some(device_name))
+}
+
+fn get_supported_configs(
+    device: &Device,
+    direction: AudioDirection,
+) -> Result<(Vec<SupportedStreamConfigRange>, SupportedStreamConfig), String> {
+    match direction {
+        AudioDirection::Input => Ok((
+            device.supported_input_configs().map_err(|err| err.to_string())?.collect(),
+            device.default_input_config().map_err(|err| err.to_string())?,
+        )),
+        AudioDirection::Output => Ok((
+            device.supported_output_configs().map_err(|err| err.to_string())?.collect(),
+            device.default_output_config().map_err(|err| err.to_string())?,
+        )),
+    }
+}
+
+pub fn get_preferred_config(device: &Device, direction: AudioDirection) -> Option<AudioDeviceConfig> {
+    let device_name = device.name().ok()?;
+    let settings = get_settings();
+    let preferred_configs = match direction {
+        AudioDirection::Input => settings.audio_input_device_configs,
+        AudioDirection::Output => settings.audio_output_device_configs,
+    };
+
+    preferred_configs.get(&device_name).cloned()
+}
+
+// the config and sample format to open a stream on the device with
+pub fn get_stream_config(device: &Device, direction: AudioDirection) -> Result<(StreamConfig, SampleFormat), MagnusError> {
+    let (supported_configs, default_config) =
+        get_supported_configs(device, direction).map_err(MagnusError::AudioDevice)?;
+
+    let preferred_config = match get_preferred_config(device, direction) {
+        Some(preferred_config) => preferred_config,
+        None => return Ok((default_config.config(), default_config.sample_format())),
+    };
+
+    let checked = match direction {
+        AudioDirection::Input => Ok(()),
+        AudioDirection::Output => check_speech_config(&preferred_config),
+    };
+    match checked.and_then(|_| find_supported_config(&supported_configs, &default_config, &preferred_config)) {
+        Ok(config) => Ok(config),
+        Err(reason) => {
+            println!(
+                "Using the default audio config for {}, {}",
+                device.name().unwrap_or_default(),
+                reason
+            );
+            Ok((default_config.config(), default_config.sample_format()))
+        }
+    }
+}
+
+// checks a preference against what the device supports without saving it
+pub fn check_device_config(
+    device: &Device,
+    direction: AudioDirection,
+    preferred_config: &AudioDeviceConfig,
+) -> Result<(), String> {
+    if direction == AudioDirection::Output {
+        check_speech_config(preferred_config)?;
+    }
+
+    let (supported_configs, default_config) = get_supported_configs(device, direction)?;
+    find_supported_config(&supported_configs, &default_config, preferred_config).map(|_| ())
+}
+
+const SPEECH_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+// whether speech can be decoded for an output stream with the preference
+fn check_speech_config(preferred_config: &AudioDeviceConfig) -> Result<(), String> {
+    if let Some(sample_rate) = preferred_config.sample_rate {
+        if !SPEECH_SAMPLE_RATES.contains(&sample_rate) {
+            return Err(format!(
+                "speech can't be played at {}Hz, use 8000, 12000, 16000, 24000 or 48000",
+                sample_rate
+            ));
+        }
+    }
+    if let Some(channels) = preferred_config.channels {
+        if !(1..=2).contains(&channels) {
+            return Err(format!("speech can't be played on {} channels, use 1 or 2", channels));
+        }
+    }
+
+    Ok(())
+}
+
+fn find_supported_config(
+    supported_configs: &[SupportedStreamConfigRange],
+    default_config: &SupportedStreamConfig,
+    preferred_config: &AudioDeviceConfig,
+) -> Result<(StreamConfig, SampleFormat), String> {
+    let channels = preferred_config.channels.unwrap_or(default_config.channels());
+    let sample_rate = SampleRate(preferred_config.sample_rate.unwrap_or(default_config.sample_rate().0));
+
+    let mut matching_configs: Vec<&SupportedStreamConfigRange> = supported_configs
+        .iter()
+        .filter(|range| {
+            range.channels() == channels
+                && range.min_sample_rate() <= sample_rate
+                && sample_rate <= range.max_sample_rate()
+        })
+        .filter(|range| match (preferred_config.buffer_size, range.buffer_size()) {
+            (Some(buffer_size), SupportedBufferSize::Range { min, max }) => *min <= buffer_size && buffer_size <= *max,
+            _ => true,
+        })
+        .collect();
+    // the default sample format is the most likely to work well
+    matching_configs.sort_by_key(|range| range.sample_format() != default_config.sample_format());
+
+    let range = match matching_configs.first() {
+        Some(range) => range,
+        None => {
+            let buffer_size = preferred_config
+                .buffer_size
+                .map(|buffer_size| format!(" with a buffer of {} frames", buffer_size))
+                .unwrap_or_default();
+            return Err(format!(
+                "it doesn't support {} channels at {}Hz{}",
+                channels, sample_rate.0, buffer_size
+            ));
+        }
+    };
+
+    let mut config = (*range).clone().with_sample_rate(sample_rate).config();
+    if let Some(buffer_size) = preferred_config.buffer_size {
+        config.buffer_size = BufferSize::Fixed(buffer_size);
+    }
+
+    Ok((config, range.sample_format()))
+}
+
+// everything the device supports, for the frontend to pick from
+pub fn get_supported_configs_json(device: &Device, direction: AudioDirection) -> Result<Value, String> {
+    let (supported_configs, default_config) = get_supported_configs(device, direction)?;
+
+    let supported = supported_configs
+        .iter()
+        .map(|range| {
+            let (min_buffer_size, max_buffer_size) = match range.buffer_size() {
+                SupportedBufferSize::Range { min, max } => (Some(*min), Some(*max)),
+                SupportedBufferSize::Unknown => (None, None),
+            };
+            json!({
+                "channels": range.channels(),
+                "minSampleRate": range.min_sample_rate().0,
+                "maxSampleRate": range.max_sample_rate().0,
+                "sampleFormat": range.sample_format().to_string(),
+                "minBufferSize": min_buffer_size,
+                "maxBufferSize": max_buffer_size
+            })
+        })
+        .collect::<Vec<Value>>();
+
+    Ok(json!({
+        "default": {
+            "channels": default_config.channels(),
+            "sampleRate": default_config.sample_rate().0,
+            "sampleFormat": default_config.sample_format().to_string()
+        },
+        "supported": supported
+    }))
+}
@@ -1,11 +1,23 @@
-use crate::{audio_input_device_selection, globals::get_vosk_model, settings};
+use crate::audio_config::{get_stream_config, AudioDirection};
+use crate::error::MagnusError;
+use crate::settings::{Permission, SpeechInputMode};
+use crate::{audio_input_device_selection, globals, globals::get_vosk_model, settings, APP_HANDLE};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, FromSample, Sample, SampleRate, StreamError};
-use crossbeam::channel::{bounded, Receiver, Sender};
+use cpal::{
+    BuildStreamError, Device, FromSample, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+    StreamError,
+};
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use dasp_interpolate::{linear::Linear, Interpolator};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{Cursor, Read};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
+use tauri::Manager;
+use tokio_util::sync::CancellationToken;
 use vosk::{DecodingState, Recognizer};
 
 pub fn get_default_audio_input_device() -> Device {
@@ -27,17 +39,11 @@ pub fn get_default_audio_input_device() -> Device {
 }
 
 pub fn get_current_audio_input_device() -> Device {
-    let settings = settings::get_settings().as_object().unwrap().clone();
-    let current_selection = settings
-        .get("audioInputDeviceSelection")
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_string();
+    let current_selection = settings::get_settings().audio_input_device_selection;
     let available_devices = get_audio_input_device_list();
 
     for device in available_devices {
-        if device.name().unwrap() == current_selection {
+        if device.name().ok() == current_selection {
             return device;
         }
     }
@@ -59,157 +65,1298 @@ pub fn get_audio_input_device_list() -> Vec<Device> {
     input_devices
 }
 
+// push to talk recordings end here even if the button is never released or the hotkey never pressed again
+const MAX_PUSH_TO_TALK_DURATION: Duration = Duration::from_secs(60);
+
+/*
+Voice activity detection decides which audio reaches the recognizer and when the user has finished talking.
+
+Audio is split into 20ms frames and a frame counts as speech when its RMS energy is well above the noise floor and its
+zero crossing rate isn't so high that it's more likely hiss than a voice. The noise floor follows the energy of the
+frames that aren't speech, so a fan or a noisy room raises the bar instead of counting as talking. Speech only starts
+after a few speech frames in a row so a click or a bump doesn't, and the frames from just before it are kept so the
+start of the first word isn't cut off. After speech the detector waits out a hangover, endOfUtteranceSilenceMs in
+settings.json, before deciding the utterance is over, so short pauses between words don't end it.
+
+The detector only deals with samples, so it can be fed a WAV file just as well as the microphone.
+*/
+const VAD_FRAME_DURATION: Duration = Duration::from_millis(20);
+const VAD_PRE_ROLL_DURATION: Duration = Duration::from_millis(300);
+const VAD_ONSET_FRAMES: u32 = 3; // speech frames in a row before speech starts
+const VAD_SPEECH_TO_NOISE_RATIO: f32 = 3.0; // how far above the noise floor a speech frame's energy has to be
+const VAD_MIN_SPEECH_RMS: f32 = 300.0; // frames quieter than this are never speech, about -40 dBFS
+const VAD_MAX_SPEECH_ZERO_CROSSINGS_PER_SECOND: f32 = 6000.0; // hiss crosses zero far more often than a voice
+const VAD_NOISE_FLOOR_RISE: f32 = 0.05; // how quickly the noise floor follows louder background noise
+const VAD_NOISE_FLOOR_FALL: f32 = 0.5; // and how quickly it follows quieter background noise
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceActivity {
+    Silence,
+    SpeechStarted,
+    Speech, // includes the hangover after the last speech frame
+    SpeechEnded,
+}
+
+// a frame's activity and the samples that should go to the recognizer for it, which is nothing during silence and
+// includes the pre-roll when speech starts
+pub struct VoiceActivityFrame {
+    pub activity: VoiceActivity,
+    pub samples: Vec<i16>,
+}
+
+pub struct VoiceActivityDetector {
+    sample_rate: u32,
+    frame_length: usize,
+    hangover_frames: u32,
+    pending: Vec<i16>, // samples that don't make up a whole frame yet
+    pre_roll: VecDeque<i16>,
+    pre_roll_length: usize,
+    noise_floor: f32,
+    onset_frames: u32,
+    hangover_remaining: u32,
+    speaking: bool,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(sample_rate: u32, hangover: Duration) -> Self {
+        let frame_length = ((sample_rate as u128 * VAD_FRAME_DURATION.as_millis()) / 1000).max(1) as usize;
+        let pre_roll_length = (sample_rate as u128 * VAD_PRE_ROLL_DURATION.as_millis() / 1000) as usize;
+
+        VoiceActivityDetector {
+            sample_rate,
+            frame_length,
+            hangover_frames: (hangover.as_millis() / VAD_FRAME_DURATION.as_millis()).max(1) as u32,
+            pending: vec![],
+            pre_roll: VecDeque::with_capacity(pre_roll_length),
+            pre_roll_length,
+            noise_floor: VAD_MIN_SPEECH_RMS / VAD_SPEECH_TO_NOISE_RATIO,
+            onset_frames: 0,
+            hangover_remaining: 0,
+            speaking: false,
+        }
+    }
+
+    pub fn is_speaking(&self) -> bool {
+        self.speaking
+    }
+
+    // classifies every whole frame in the samples, anything left over waits for the next call
+    pub fn push(&mut self, samples: &[i16]) -> Vec<VoiceActivityFrame> {
+        self.pending.extend_from_slice(samples);
+
+        let whole_frames_length = self.pending.len() - self.pending.len() % self.frame_length;
+        let frames: Vec<i16> = self.pending.drain(..whole_frames_length).collect();
+
+        frames
+            .chunks(self.frame_length)
+            .map(|frame| self.push_frame(frame))
+            .collect()
+    }
+
+    fn push_frame(&mut self, frame: &[i16]) -> VoiceActivityFrame {
+        let is_speech = self.is_speech_frame(frame);
+
+        if self.speaking {
+            let activity = if is_speech {
+                self.hangover_remaining = self.hangover_frames;
+                VoiceActivity::Speech
+            } else if self.hangover_remaining > 1 {
+                self.hangover_remaining -= 1;
+                VoiceActivity::Speech
+            } else {
+                self.speaking = false;
+                self.onset_frames = 0;
+                VoiceActivity::SpeechEnded
+            };
+
+            return VoiceActivityFrame {
+                activity,
+                samples: frame.to_vec(),
+            };
+        }
+
+        if is_speech {
+            self.onset_frames += 1;
+        } else {
+            self.onset_frames = 0;
+        }
+
+        if self.onset_frames >= VAD_ONSET_FRAMES {
+            self.speaking = true;
+            self.hangover_remaining = self.hangover_frames;
+
+            let mut samples: Vec<i16> = self.pre_roll.drain(..).collect();
+            samples.extend_from_slice(frame);
+            return VoiceActivityFrame {
+                activity: VoiceActivity::SpeechStarted,
+                samples,
+            };
+        }
+
+        // kept in case this is the start of speech
+        self.pre_roll.extend(frame.iter());
+        let excess = self.pre_roll.len().saturating_sub(self.pre_roll_length);
+        self.pre_roll.drain(..excess);
+
+        VoiceActivityFrame {
+            activity: VoiceActivity::Silence,
+            samples: vec![],
+        }
+    }
+
+    fn is_speech_frame(&mut self, frame: &[i16]) -> bool {
+        let rms = get_rms(frame);
+        let zero_crossings_per_second =
+            get_zero_crossing_rate(frame) * self.sample_rate as f32;
+
+        let is_speech = rms >= VAD_MIN_SPEECH_RMS
+            && rms >= self.noise_floor * VAD_SPEECH_TO_NOISE_RATIO
+            && zero_crossings_per_second <= VAD_MAX_SPEECH_ZERO_CROSSINGS_PER_SECOND;
+
+        // the noise floor only learns from background noise, never from the user talking
+        if !is_speech && !self.speaking {
+            let adaptation = if rms > self.noise_floor {
+                VAD_NOISE_FLOOR_RISE
+            } else {
+                VAD_NOISE_FLOOR_FALL
+            };
+            self.noise_floor += (rms - self.noise_floor) * adaptation;
+        }
+
+        is_speech
+    }
+}
+
+fn get_rms(frame: &[i16]) -> f32 {
+    if frame.is_empty() {
+        return 0.0;
+    }
+    let sum_of_squares: f64 = frame.iter().map(|&sample| (sample as f64).powi(2)).sum();
+    (sum_of_squares / frame.len() as f64).sqrt() as f32
+}
+
+// the fraction of neighbouring samples that have opposite signs
+fn get_zero_crossing_rate(frame: &[i16]) -> f32 {
+    if frame.len() < 2 {
+        return 0.0;
+    }
+    let zero_crossings = frame
+        .windows(2)
+        .filter(|pair| (pair[0] >= 0) != (pair[1] >= 0))
+        .count();
+    zero_crossings as f32 / (frame.len() - 1) as f32
+}
+
+// what ended a capture, sent to the frontend in the "capture-ended" event
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CaptureEndReason {
+    Silence, // the user was quiet for endOfUtteranceSilenceMs after talking
+    NoSpeech, // nothing was said within noSpeechTimeoutMs
+    NoAudio, // the input device sent nothing for noAudioTimeoutMs, or its stream stopped
+    Released, // the push to talk button was released, or its hotkey pressed again
+    MaxDuration, // push to talk went on for too long
+    Cancelled,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CaptureEnded {
+    reason: CaptureEndReason,
+    transcription: Option<String>,
+    duration_ms: u64,
+}
+
+// transcripts the user didn't mean as a question, like a cough recognized as "huh"
+fn is_filler(transcription: &str, filler_transcripts: &[String]) -> bool {
+    let transcription = transcription.trim();
+    transcription.is_empty()
+        || filler_transcripts
+            .iter()
+            .any(|filler| filler.trim().eq_ignore_ascii_case(transcription))
+}
+
+// the recognizer splits an utterance into segments at its own pauses, this puts them back together without fillers
+fn join_segments(segments: &[String], filler_transcripts: &[String]) -> Option<String> {
+    let transcription = segments
+        .iter()
+        .filter(|segment| !is_filler(segment, filler_transcripts))
+        .map(|segment| segment.trim())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if transcription.is_empty() {
+        None
+    } else {
+        Some(transcription)
+    }
+}
+
+// the recognizer behind the voice activity detector, the same for the microphone and for audio files
+struct Transcriber {
+    recognizer: Recognizer,
+    voice_activity_detector: VoiceActivityDetector,
+    segments: Vec<String>, // what the recognizer finalized so far
+}
+
+impl Transcriber {
+    fn new(sample_rate: SampleRate, end_of_utterance_silence: Duration) -> Option<Self> {
+        Some(Transcriber {
+            recognizer: Recognizer::new(&get_vosk_model(), sample_rate.0 as f32)?,
+            voice_activity_detector: VoiceActivityDetector::new(sample_rate.0, end_of_utterance_silence),
+            segments: vec![],
+        })
+    }
+
+    fn is_speaking(&self) -> bool {
+        self.voice_activity_detector.is_speaking()
+    }
+
+    // feeds the speech in the samples to the recognizer, returning true if an utterance ended in them
+    fn accept(&mut self, samples: &[i16]) -> bool {
+        let mut utterance_ended = false;
+
+        for frame in self.voice_activity_detector.push(samples) {
+            match frame.activity {
+                VoiceActivity::Silence => {}
+                VoiceActivity::SpeechStarted | VoiceActivity::Speech => self.accept_speech(&frame.samples),
+                VoiceActivity::SpeechEnded => {
+                    self.accept_speech(&frame.samples);
+                    self.finish();
+                    utterance_ended = true;
+                }
+            }
+        }
+
+        utterance_ended
+    }
+
+    // keeps each segment the recognizer finalizes
+    fn accept_speech(&mut self, samples: &[i16]) {
+        if self.recognizer.accept_waveform(samples) == DecodingState::Finalized {
+            if let Some(result) = self.recognizer.result().single() {
+                self.segments.push(result.text.to_string());
+            }
+        }
+    }
+
+    // whatever was said since the recognizer's last segment
+    fn finish(&mut self) {
+        if let Some(result) = self.recognizer.final_result().single() {
+            self.segments.push(result.text.to_string());
+        }
+    }
+}
+
+// vosk doesn't say why it couldn't create a recognizer
+fn get_recognizer_error(sample_rate: SampleRate) -> MagnusError {
+    MagnusError::AudioDevice(format!("the speech recognizer couldn't be started at {}Hz", sample_rate.0))
+}
+
+// transcribes until the user stops talking, or for push to talk until release_token is cancelled
 pub fn run_transcription(
     audio_input_receiver: Receiver<Vec<i16>>,
     sample_rate: SampleRate,
-) -> Option<String> {
-    let mut recognizer = Recognizer::new(&get_vosk_model(), sample_rate.0 as f32).unwrap();
+    cancel_token: CancellationToken,
+    release_token: Option<CancellationToken>,
+) -> Result<(Option<String>, CaptureEndReason), MagnusError> {
+    if let Some(release_token) = release_token {
+        return run_push_to_talk_transcription(audio_input_receiver, sample_rate, cancel_token, release_token);
+    }
+
+    let settings = settings::get_settings();
+    let no_speech_timeout = Duration::from_millis(settings.no_speech_timeout_ms);
+    let no_audio_timeout = Duration::from_millis(settings.no_audio_timeout_ms);
+    let end_of_utterance_silence = Duration::from_millis(settings.end_of_utterance_silence_ms);
+
+    let mut transcriber = Transcriber::new(sample_rate, end_of_utterance_silence)
+        .ok_or_else(|| get_recognizer_error(sample_rate))?;
     println!("Speak..."); // eventually it would be nice to emit an audio cue telling the user they can speak
 
-    // start "timer" here
-    let transcription_start_time = Instant::now();
+    // start "timer" here, it restarts when an utterance turns out to be filler
+    let mut waiting_for_speech_since = Instant::now();
     let mut data_last_received = Instant::now();
 
     loop {
-        if let Ok(data) = audio_input_receiver.try_recv() {
-            data_last_received = Instant::now();
-            let decoding_state = recognizer.accept_waveform(data.as_slice());
-            if decoding_state == DecodingState::Finalized {
-                // silence detected
-                let transcription = recognizer.final_result().single().unwrap().text.to_string();
-
-                if transcription != "huh".to_string() {
-                    return Some(transcription);
+        if cancel_token.is_cancelled() {
+            println!("Transcription cancelled.");
+            return Ok((None, CaptureEndReason::Cancelled));
+        }
+
+        match audio_input_receiver.recv_timeout(Duration::from_millis(50)) {
+            Ok(data) => {
+                data_last_received = Instant::now();
+                if transcriber.accept(&data) {
+                    if let Some(transcription) = join_segments(&transcriber.segments, &settings.filler_transcripts) {
+                        return Ok((Some(transcription), CaptureEndReason::Silence));
+                    }
+                    println!("Ignoring filler transcript \"{}\"", transcriber.segments.join(" "));
+                    transcriber.segments.clear();
+                    waiting_for_speech_since = Instant::now();
                 }
-            } else if decoding_state == DecodingState::Running {
-                // if partial result is nothing and its been 3 seconds or more since the timer started, return None
-                // without this, transcription will run until something has been said
-                let partial = recognizer.partial_result().partial;
-
-                if partial == "" && transcription_start_time.elapsed() >= Duration::from_secs(3) {
-                    println!("Nothing said after 3 seconds");
-                    return None;
+            }
+            // the input stream stopped
+            Err(RecvTimeoutError::Disconnected) => return Ok((None, CaptureEndReason::NoAudio)),
+            Err(RecvTimeoutError::Timeout) => {
+                if data_last_received.elapsed() >= no_audio_timeout {
+                    println!("No audio received for {}ms, exiting transcription.", settings.no_audio_timeout_ms);
+                    return Ok((None, CaptureEndReason::NoAudio));
                 }
             }
-        } else if data_last_received.elapsed() >= Duration::from_secs(3) {
-            println!("No audio received for 3 seconds, exitiing transcription.");
-            return None;
+        }
+
+        // without this, transcription will run until something has been said
+        if !transcriber.is_speaking() && waiting_for_speech_since.elapsed() >= no_speech_timeout {
+            println!("Nothing said after {}ms", settings.no_speech_timeout_ms);
+            return Ok((None, CaptureEndReason::NoSpeech));
         }
     }
 }
 
+// the whole recording is transcribed, pauses only split it into segments that are joined back together
+fn run_push_to_talk_transcription(
+    audio_input_receiver: Receiver<Vec<i16>>,
+    sample_rate: SampleRate,
+    cancel_token: CancellationToken,
+    release_token: CancellationToken,
+) -> Result<(Option<String>, CaptureEndReason), MagnusError> {
+    let settings = settings::get_settings();
+    // the user decides when they're done, the detector only keeps silence away from the recognizer
+    let end_of_utterance_silence = Duration::from_millis(settings.end_of_utterance_silence_ms);
+    let mut transcriber = match Transcriber::new(sample_rate, end_of_utterance_silence) {
+        Some(transcriber) => transcriber,
+        None => {
+            release_token.cancel();
+            return Err(get_recognizer_error(sample_rate));
+        }
+    };
+    println!("Recording until released...");
+
+    let recording_start_time = Instant::now();
+
+    let end_reason = loop {
+        if cancel_token.is_cancelled() {
+            println!("Transcription cancelled.");
+            release_token.cancel();
+            return Ok((None, CaptureEndReason::Cancelled));
+        }
+        if release_token.is_cancelled() {
+            break CaptureEndReason::Released;
+        }
+        if recording_start_time.elapsed() >= MAX_PUSH_TO_TALK_DURATION {
+            println!("Push to talk went on for too long, stopping the recording.");
+            release_token.cancel();
+            break CaptureEndReason::MaxDuration;
+        }
+
+        match audio_input_receiver.recv_timeout(Duration::from_millis(50)) {
+            Ok(data) => {
+                transcriber.accept(&data);
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                release_token.cancel();
+                break CaptureEndReason::NoAudio;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    };
+
+    transcriber.finish();
+
+    let transcription = join_segments(&transcriber.segments, &settings.filler_transcripts);
+    if transcription.is_none() {
+        println!("Nothing said while recording");
+    }
+    Ok((transcription, end_reason))
+}
+
 fn run_stream(
     audio_input_sender: Sender<Vec<i16>>,
     device: Device,
     transcribing: Arc<Mutex<bool>>,
-) {
-    let config = device.default_input_config().unwrap();
+) -> Result<(), MagnusError> {
+    let (config, sample_format) = get_stream_config(&device, AudioDirection::Input)?;
     let (error_sender, error_receiver): (Sender<StreamError>, Receiver<StreamError>) = bounded(1);
 
     fn error_callback(e: StreamError, error_sender: Sender<StreamError>) {
         error_sender.send(e).ok();
     }
 
-
-    let callback_transcription = transcribing.clone();
-    fn write_data<T>(data: &[T], channels: u16, audio_input_sender: Sender<Vec<i16>>, transcribing: Arc<Mutex<bool>>)
-    where
+    fn write_data<T>(
+        data: &[T],
+        channels: u16,
+        resampler: &mut Resampler,
+        audio_input_sender: Sender<Vec<i16>>,
+        transcribing: Arc<Mutex<bool>>,
+    ) where
         T: Sample,
-        i16: FromSample<T>,
+        f32: FromSample<T>,
     {
-        let mut buffer: Vec<i16> = vec![];
-        for frame in data.chunks(channels.into()) {
-            buffer.push(frame[0].to_sample::<i16>());
-        }
+        let mono = downmix(data, channels);
+        let mut buffer: Vec<i16> = Vec::with_capacity(mono.len());
+        resampler.process(&mono, &mut buffer);
 
         match audio_input_sender.try_send(buffer) {
             Ok(_) => {}
             Err(e) => {
                 if e.is_disconnected() && *transcribing.lock().unwrap() {
-                    panic!("Audio input channel disconnected!")
+                    println!("Audio input channel disconnected!")
                 }
             }
         }
     }
 
-    let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => device.build_input_stream(
-            &config.clone().into(),
-            move |data: &[f32], _: &_| {
-                write_data(data, config.channels(), audio_input_sender.clone(), callback_transcription.clone())
-            },
-            move |e| error_callback(e, error_sender.clone()),
-            None,
-        ),
-        cpal::SampleFormat::I16 => device.build_input_stream(
-            &config.clone().into(),
-            move |data: &[i16], _: &_| {
-                write_data(data, config.channels(), audio_input_sender.clone(), callback_transcription.clone())
-            },
-            move |e| error_callback(e, error_sender.clone()),
-            None,
-        ),
-        cpal::SampleFormat::U16 => device.build_input_stream(
-            &config.clone().into(),
-            move |data: &[u16], _: &_| {
-                write_data(data, config.channels(), audio_input_sender.clone(), callback_transcription.clone())
+    // the same stream for every sample format, T is the format's sample type
+    fn build_stream<T>(
+        device: &Device,
+        config: &StreamConfig,
+        audio_input_sender: Sender<Vec<i16>>,
+        transcribing: Arc<Mutex<bool>>,
+        error_sender: Sender<StreamError>,
+    ) -> Result<Stream, BuildStreamError>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let channels = config.channels;
+        let mut resampler = Resampler::new(config.sample_rate.0, RECOGNIZER_SAMPLE_RATE.0);
+        device.build_input_stream(
+            config,
+            move |data: &[T], _: &_| {
+                write_data(data, channels, &mut resampler, audio_input_sender.clone(), transcribing.clone())
             },
             move |e| error_callback(e, error_sender.clone()),
             None,
-        ),
-        _ => panic!(),
+        )
+    }
+
+    let sender = audio_input_sender;
+    let callback_transcribing = transcribing.clone();
+    let stream = match sample_format {
+        SampleFormat::I8 => build_stream::<i8>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::I64 => build_stream::<i64>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::U8 => build_stream::<u8>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::U32 => build_stream::<u32>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::U64 => build_stream::<u64>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, sender, callback_transcribing, error_sender),
+        SampleFormat::F64 => build_stream::<f64>(&device, &config, sender, callback_transcribing, error_sender),
+        // cpal may add formats in the future
+        sample_format => {
+            return Err(MagnusError::AudioDevice(format!(
+                "unsupported input sample format {}",
+                sample_format
+            )))
+        }
     }
-    .expect("Failed to build audio input stream!");
+    .map_err(|err| MagnusError::AudioDevice(format!("failed to build audio input stream: {}", err)))?;
 
     match stream.play() {
         Ok(_) => {
             println!("Successfully started audio input stream!")
         }
-        Err(error) => println!("Failed to start audio input stream: {}", error),
+        Err(error) => {
+            return Err(MagnusError::AudioDevice(format!(
+                "failed to start audio input stream: {}",
+                error
+            )))
+        }
     }
 
+    // waits on the error channel rather than spinning, the wake word listener keeps this stream open all the time
     loop {
-        if let Ok(_stream_error) = error_receiver.try_recv() {
+        if let Ok(stream_error) = error_receiver.recv_timeout(Duration::from_millis(50)) {
             println!("ERROR OCCURRED ON INPUT STREAM");
-            break;
+            return Err(MagnusError::AudioDevice(stream_error.to_string()));
         } else if !*transcribing.lock().unwrap() {
             println!("Transcription finished, exiting input stream.");
-            break;
+            return Ok(());
         }
     }
 }
 
-pub fn run() -> Option<String> {
+/*
+The microphone is only ever opened by capture, so checking the Microphone permission here covers every way of starting
+to listen: the mic button, push to talk, hotkeys and the wake word. Nothing can confirm the microphone while it's being
+listened to, so "ask" in permissions.json counts as denied, the same as for background listening.
+*/
+pub fn check_microphone_permission() -> Result<(), MagnusError> {
+    let to_confirm = settings::check_permissions(vec![Permission::Microphone])?;
+    if to_confirm.is_empty() {
+        Ok(())
+    } else {
+        Err(MagnusError::PermissionDenied(vec![Permission::Microphone.as_str().to_string()]))
+    }
+}
+
+/*
+The small Vosk model is trained on 16kHz mono audio, while microphones usually record at 44.1 or 48kHz and often in
+stereo. Every frame is mixed down to mono by averaging its channels and then resampled to 16kHz before anything else
+sees it. The recognizer is more accurate at the rate it was trained on, and it and the voice activity detector have a
+third of the samples to get through.
+*/
+pub const RECOGNIZER_SAMPLE_RATE: SampleRate = SampleRate(16000);
+
+// averages each frame's channels into one sample
+pub fn downmix<T>(data: &[T], channels: u16) -> Vec<f32>
+where
+    T: Sample,
+    f32: FromSample<T>,
+{
+    let channels = channels.max(1) as usize;
+    data.chunks(channels)
+        .map(|frame| frame.iter().map(|sample| sample.to_sample::<f32>()).sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+/*
+Converts mono audio from one sample rate to another a buffer at a time, keeping its place between buffers.
+
+Linear interpolation on its own only looks at the two source samples either side of each output sample, so going down
+to a lower rate, anything above the new rate's Nyquist frequency (8kHz for 16kHz) folds back into the audio as noise
+the recognizer tries to make words out of. Before it gets to the interpolator the audio goes through a windowed sinc
+low-pass filter that cuts off a little under that frequency. Going up to a higher rate nothing can fold back, so the
+filter is skipped.
+*/
+const RESAMPLER_LOW_PASS_TAPS: usize = 63; // odd, so the filter is centered on a sample
+const RESAMPLER_CUTOFF: f64 = 0.9; // of the new rate's Nyquist frequency, leaving room for the filter to roll off
+
+pub struct Resampler {
+    interpolator: Linear<f32>,
+    step: f64, // how far through the source audio each output sample moves
+    position: f64, // where the next output sample falls, counted from the interpolator's left sample
+    low_pass_taps: Vec<f32>, // empty when the rate isn't going down
+    history: VecDeque<f32>, // the last source samples, as many as there are taps
+}
+
+impl Resampler {
+    pub fn new(from_hz: u32, to_hz: u32) -> Self {
+        let low_pass_taps = if to_hz < from_hz {
+            get_low_pass_taps(RESAMPLER_CUTOFF * 0.5 * to_hz as f64 / from_hz as f64)
+        } else {
+            vec![]
+        };
+
+        Resampler {
+            interpolator: Linear::new(0.0, 0.0),
+            step: from_hz as f64 / to_hz as f64,
+            // nothing comes out until the interpolator has two real samples to go between
+            position: 2.0,
+            history: VecDeque::from(vec![0.0; low_pass_taps.len()]),
+            low_pass_taps,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32], output: &mut Vec<i16>) {
+        for &sample in samples {
+            let sample = self.low_pass(sample);
+            self.interpolator.next_source_frame(sample);
+            self.position -= 1.0;
+
+            while self.position < 1.0 {
+                output.push(self.interpolator.interpolate(self.position).to_sample::<i16>());
+                self.position += self.step;
+            }
+        }
+    }
+
+    fn low_pass(&mut self, sample: f32) -> f32 {
+        if self.low_pass_taps.is_empty() {
+            return sample;
+        }
+
+        self.history.pop_front();
+        self.history.push_back(sample);
+        self.history.iter().zip(&self.low_pass_taps).map(|(sample, tap)| sample * tap).sum()
+    }
+}
+
+// a Hann windowed sinc filter passing frequencies below cutoff, given as a fraction of the sample rate
+fn get_low_pass_taps(cutoff: f64) -> Vec<f32> {
+    let middle = (RESAMPLER_LOW_PASS_TAPS - 1) as f64 / 2.0;
+    let taps: Vec<f64> = (0..RESAMPLER_LOW_PASS_TAPS)
+        .map(|i| {
+            let n = i as f64 - middle;
+            let sinc = if n == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * std::f64::consts::PI * cutoff * n).sin() / (std::f64::consts::PI * n)
+            };
+            let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / (RESAMPLER_LOW_PASS_TAPS - 1) as f64).cos();
+            sinc * window
+        })
+        .collect();
+
+    // scaled so the taps add up to 1 and steady audio keeps its level
+    let sum: f64 = taps.iter().sum();
+    taps.iter().map(|tap| (tap / sum) as f32).collect()
+}
+
+// runs the input stream for as long as transcribe takes, giving it the audio and the stream's sample rate
+pub fn capture<T, F>(transcribe: F) -> Result<T, MagnusError>
+where
+    T: Send + 'static,
+    F: FnOnce(Receiver<Vec<i16>>, SampleRate) -> T + Send + 'static,
+{
+    check_microphone_permission()?;
+
     let (audio_input_sender, audio_input_receiver): (Sender<Vec<i16>>, Receiver<Vec<i16>>) =
         bounded::<Vec<i16>>(1);
     let transcribing: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
 
     // find an input device
     let audio_input_device = get_current_audio_input_device();
-    let audio_input_config = audio_input_device.default_input_config().unwrap();
 
-    // spawn the transcription thread
-    let audio_input_receiver_clone = audio_input_receiver.clone();
+    // spawn the transcription thread, the stream resamples everything to the recognizer's rate
     *transcribing.lock().unwrap() = true;
-    let transcription_handle = thread::spawn(move || {
-        run_transcription(audio_input_receiver_clone, audio_input_config.sample_rate())
-    });
+    let transcription_handle =
+        thread::spawn(move || transcribe(audio_input_receiver, RECOGNIZER_SAMPLE_RATE));
 
-    // run input stream until we are no longer transcribing
+    // run input stream until we are no longer transcribing. the sender is moved so that the receiver disconnects if
+    // the stream stops on its own
     let transcribing_clone = transcribing.clone();
-    let audio_input_sender_clone = audio_input_sender.clone();
     let input_stream_handle = thread::spawn(move || {
         run_stream(
-            audio_input_sender_clone,
+            audio_input_sender,
             audio_input_device,
             transcribing_clone,
-        );
+        )
     });
 
     // wait for transcription and input streams to finish before returning the transcription
     let transcription = transcription_handle.join().unwrap();
     *transcribing.lock().unwrap() = false;
-    let _ = input_stream_handle.join().unwrap();
+    input_stream_handle.join().unwrap()?;
+
+    Ok(transcription)
+}
 
-    transcription
+pub fn emit_capture_ended(end_reason: CaptureEndReason, transcription: &Option<String>, capture_start_time: Instant) {
+    println!("Capture ended: {:?}", end_reason);
+    let app_handle = APP_HANDLE.lock().unwrap().clone();
+    if let Some(app_handle) = app_handle {
+        let _ = app_handle.emit_all(
+            "capture-ended",
+            CaptureEnded {
+                reason: end_reason,
+                transcription: transcription.clone(),
+                duration_ms: capture_start_time.elapsed().as_millis() as u64,
+            },
+        );
+    }
+}
+
+pub fn run(cancel_token: CancellationToken) -> Result<Option<String>, MagnusError> {
+    // with push to talk the recording lasts until the button is released or the hotkey is pressed again
+    let release_token = match settings::get_settings().speech_input_mode {
+        SpeechInputMode::PushToTalk => Some(globals::get_push_to_talk_token()),
+        SpeechInputMode::Silence => None,
+    };
+
+    let capture_start_time = Instant::now();
+    let (transcription, end_reason) = capture(move |audio_input_receiver, sample_rate| {
+        run_transcription(audio_input_receiver, sample_rate, cancel_token, release_token)
+    })??;
+    emit_capture_ended(end_reason, &transcription, capture_start_time);
+
+    Ok(transcription)
+}
+
+/*
+Audio files go through the same resampling, voice activity detection and recognizer as the microphone, so a voice note
+is heard the same way a question asked out loud is. Unlike the microphone the whole file is transcribed, pauses and
+all, since there's nobody to wait for.
+
+WAV files with integer or float samples and Ogg files holding Opus are supported, told apart by how they start rather
+than by their extension. Files come from the transcribe_file command, or from the command line with
+`magnus --transcribe <path>`, where a path of "-" reads the file from stdin instead (see main). Only the command line
+reads stdin, the app has nothing to read it from and could be left waiting on it forever.
+*/
+pub fn transcribe_file(path: &str) -> Result<Option<String>, MagnusError> {
+    let bytes = std::fs::read(path).map_err(|err| MagnusError::AudioFile(format!("couldn't read {}: {}", path, err)))?;
+    transcribe_audio_with_settings(&bytes)
+}
+
+pub fn transcribe_stdin() -> Result<Option<String>, MagnusError> {
+    let mut bytes: Vec<u8> = vec![];
+    std::io::stdin()
+        .read_to_end(&mut bytes)
+        .map_err(|err| MagnusError::AudioFile(format!("couldn't read stdin: {}", err)))?;
+    transcribe_audio_with_settings(&bytes)
+}
+
+fn transcribe_audio_with_settings(bytes: &[u8]) -> Result<Option<String>, MagnusError> {
+    let settings = settings::get_settings();
+    transcribe_audio(
+        bytes,
+        Duration::from_millis(settings.end_of_utterance_silence_ms),
+        &settings.filler_transcripts,
+    )
+}
+
+// transcribes a whole WAV or Ogg/Opus file that's already in memory, without needing settings.json
+pub fn transcribe_audio(
+    bytes: &[u8],
+    end_of_utterance_silence: Duration,
+    filler_transcripts: &[String],
+) -> Result<Option<String>, MagnusError> {
+    let samples = if bytes.starts_with(b"RIFF") {
+        let (samples, sample_rate) = decode_wav(bytes).map_err(MagnusError::AudioFile)?;
+        let mut resampled: Vec<i16> = Vec::with_capacity(samples.len());
+        Resampler::new(sample_rate, RECOGNIZER_SAMPLE_RATE.0).process(&samples, &mut resampled);
+        resampled
+    } else if bytes.starts_with(b"OggS") {
+        decode_ogg_opus(bytes).map_err(MagnusError::AudioFile)?
+    } else {
+        return Err(MagnusError::AudioFile("only WAV and Ogg/Opus files are supported".to_string()));
+    };
+
+    let mut transcriber = Transcriber::new(RECOGNIZER_SAMPLE_RATE, end_of_utterance_silence)
+        .ok_or_else(|| MagnusError::AudioFile("couldn't start the recognizer".to_string()))?;
+
+    // fed in buffers about the size the microphone sends
+    for buffer in samples.chunks(RECOGNIZER_SAMPLE_RATE.0 as usize / 10) {
+        transcriber.accept(buffer);
+    }
+    transcriber.finish();
+
+    Ok(join_segments(&transcriber.segments, filler_transcripts))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// mono samples and their sample rate from a WAV file
+fn decode_wav(bytes: &[u8]) -> Result<(Vec<f32>, u32), String> {
+    const PCM: u16 = 1;
+    const IEEE_FLOAT: u16 = 3;
+    const EXTENSIBLE: u16 = 0xFFFE;
+
+    if bytes.get(8..12) != Some(b"WAVE".as_slice()) {
+        return Err("the file isn't a WAV file".to_string());
+    }
+
+    // (format, channels, sample rate, bits per sample)
+    let mut format: Option<(u16, u16, u32, u16)> = None;
+    let mut data: Option<&[u8]> = None;
+
+    // the file is a list of chunks, only "fmt " and "data" matter
+    let mut offset = 12;
+    while let (Some(id), Some(size)) = (bytes.get(offset..offset + 4), read_u32(bytes, offset + 4)) {
+        let body_start = offset + 8;
+        let body = bytes
+            .get(body_start..body_start + size as usize)
+            .unwrap_or(bytes.get(body_start..).unwrap_or_default());
+
+        match id {
+            b"fmt " => {
+                let mut audio_format = read_u16(body, 0).ok_or("the format chunk is too short")?;
+                // extensible files keep the real format at the start of their subformat
+                if audio_format == EXTENSIBLE {
+                    audio_format = read_u16(body, 24).ok_or("the format chunk is too short")?;
+                }
+                format = Some((
+                    audio_format,
+                    read_u16(body, 2).ok_or("the format chunk is too short")?,
+                    read_u32(body, 4).ok_or("the format chunk is too short")?,
+                    read_u16(body, 14).ok_or("the format chunk is too short")?,
+                ));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+
+        // chunks are padded to an even length
+        offset = body_start + size as usize + (size as usize % 2);
+    }
+
+    let (audio_format, channels, sample_rate, bits_per_sample) = format.ok_or("the file has no format chunk")?;
+    let data = data.ok_or("the file has no audio data")?;
+    if channels == 0 || sample_rate == 0 {
+        return Err("the file has no channels or no sample rate".to_string());
+    }
+
+    let samples: Vec<f32> = match (audio_format, bits_per_sample) {
+        (PCM, 8) => data.iter().map(|&sample| sample.to_sample::<f32>()).collect(),
+        (PCM, 16) => data
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).to_sample::<f32>())
+            .collect(),
+        // 24 bit samples are moved into the top of an i32 so they keep their sign
+        (PCM, 24) => data
+            .chunks_exact(3)
+            .map(|sample| i32::from_le_bytes([0, sample[0], sample[1], sample[2]]).to_sample::<f32>())
+            .collect(),
+        (PCM, 32) => data
+            .chunks_exact(4)
+            .map(|sample| i32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]).to_sample::<f32>())
+            .collect(),
+        (IEEE_FLOAT, 32) => data
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect(),
+        (IEEE_FLOAT, 64) => data
+            .chunks_exact(8)
+            .map(|sample| f64::from_le_bytes(sample.try_into().unwrap()).to_sample::<f32>())
+            .collect(),
+        (audio_format, bits_per_sample) => {
+            return Err(format!(
+                "WAV format {} with {} bit samples isn't supported",
+                audio_format, bits_per_sample
+            ))
+        }
+    };
+
+    Ok((downmix(&samples, channels), sample_rate))
+}
+
+// Opus can decode straight to the recognizer's rate and to mono, whatever the file was recorded with
+fn decode_ogg_opus(bytes: &[u8]) -> Result<Vec<i16>, String> {
+    let mut packet_reader = ogg::reading::PacketReader::new(Cursor::new(bytes));
+    let mut opus_decoder = opus::Decoder::new(RECOGNIZER_SAMPLE_RATE.0, opus::Channels::Mono)
+        .map_err(|err| format!("couldn't start the Opus decoder: {}", err))?;
+
+    // room for the longest Opus frame, 120ms
+    let mut decoded: Vec<i16> = vec![0; RECOGNIZER_SAMPLE_RATE.0 as usize * 120 / 1000];
+    let mut samples: Vec<i16> = vec![];
+
+    while let Some(packet) = packet_reader
+        .read_packet()
+        .map_err(|err| format!("couldn't read the Ogg file: {}", err))?
+    {
+        // the header packets describe the stream, they aren't audio
+        if packet.data.starts_with(b"OpusHead") || packet.data.starts_with(b"OpusTags") {
+            continue;
+        }
+
+        let length = opus_decoder
+            .decode(&packet.data, &mut decoded, false)
+            .map_err(|err| format!("couldn't decode the Opus audio: {}", err))?;
+        samples.extend_from_slice(&decoded[..length]);
+    }
+
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the fixtures are 8kHz mono, so a 20ms frame is 160 samples
+    const FIXTURE_FRAME_LENGTH: usize = 160;
+
+    fn read_fixture(name: &str) -> (Vec<i16>, u32) {
+        let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let bytes = std::fs::read(&path).unwrap();
+        let (samples, sample_rate) = decode_wav(&bytes).unwrap();
+        (samples.iter().map(|sample| sample.to_sample::<i16>()).collect(), sample_rate)
+    }
+
+    // the index of every frame that isn't Silence or Speech, with its activity
+    fn get_boundaries(frames: &[VoiceActivityFrame]) -> Vec<(usize, VoiceActivity)> {
+        frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| matches!(frame.activity, VoiceActivity::SpeechStarted | VoiceActivity::SpeechEnded))
+            .map(|(index, frame)| (index, frame.activity))
+            .collect()
+    }
+
+    #[test]
+    fn rms_of_nothing_is_zero() {
+        assert_eq!(get_rms(&[]), 0.0);
+        assert_eq!(get_rms(&[0; 160]), 0.0);
+    }
+
+    #[test]
+    fn rms_ignores_sign() {
+        assert_eq!(get_rms(&[1000; 160]), 1000.0);
+        assert_eq!(get_rms(&[3000, -3000, 3000, -3000]), 3000.0);
+        assert_eq!(get_rms(&[i16::MIN, i16::MIN]), 32768.0);
+        assert!((get_rms(&[3, 4]) - 12.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn zero_crossing_rate_needs_two_samples() {
+        assert_eq!(get_zero_crossing_rate(&[]), 0.0);
+        assert_eq!(get_zero_crossing_rate(&[-5]), 0.0);
+    }
+
+    #[test]
+    fn zero_crossing_rate_counts_sign_changes() {
+        assert_eq!(get_zero_crossing_rate(&[1, 2, 3, 4]), 0.0);
+        assert_eq!(get_zero_crossing_rate(&[-1, -2, -3]), 0.0);
+        assert_eq!(get_zero_crossing_rate(&[1, -1, 1, -1, 1]), 1.0);
+        assert_eq!(get_zero_crossing_rate(&[1, -1, -1, 1]), 2.0 / 3.0);
+        // zero counts as positive
+        assert_eq!(get_zero_crossing_rate(&[0, 1, 0]), 0.0);
+        assert_eq!(get_zero_crossing_rate(&[0, -1]), 1.0);
+    }
+
+    #[test]
+    fn speech_starts_and_ends_at_the_right_frames() {
+        // 0.6s of quiet, 0.6s of voice, a 0.2s pause, 0.6s of voice and 1s of quiet
+        let (samples, sample_rate) = read_fixture("vad_speech_with_pause.wav");
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let frames = detector.push(&samples);
+
+        assert_eq!(frames.len(), samples.len() / FIXTURE_FRAME_LENGTH);
+        // the voice starts at frame 30 and speech starts on its third frame. the last voice frame is 99, and the 25th
+        // quiet frame after it fills the 500ms hangover
+        assert_eq!(
+            get_boundaries(&frames),
+            vec![(32, VoiceActivity::SpeechStarted), (124, VoiceActivity::SpeechEnded)]
+        );
+        assert!(frames[..32].iter().all(|frame| frame.activity == VoiceActivity::Silence));
+        assert!(frames[33..124].iter().all(|frame| frame.activity == VoiceActivity::Speech));
+        assert!(frames[125..].iter().all(|frame| frame.activity == VoiceActivity::Silence));
+        assert!(!detector.is_speaking());
+    }
+
+    #[test]
+    fn speech_started_includes_the_pre_roll() {
+        let (samples, sample_rate) = read_fixture("vad_speech_with_pause.wav");
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let frames = detector.push(&samples);
+
+        let pre_roll_length = (sample_rate / 1000 * VAD_PRE_ROLL_DURATION.as_millis() as u32) as usize;
+        let frame_end = 33 * FIXTURE_FRAME_LENGTH;
+        assert_eq!(frames[32].samples, samples[frame_end - FIXTURE_FRAME_LENGTH - pre_roll_length..frame_end]);
+
+        // only silence goes without samples
+        for frame in &frames {
+            assert_eq!(frame.samples.is_empty(), frame.activity == VoiceActivity::Silence);
+        }
+    }
+
+    #[test]
+    fn hangover_keeps_short_pauses_in_the_utterance() {
+        let (samples, sample_rate) = read_fixture("vad_speech_with_pause.wav");
+
+        // the 200ms pause is shorter than a 500ms hangover, so it's one utterance
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let boundaries = get_boundaries(&detector.push(&samples));
+        assert_eq!(boundaries.len(), 2);
+
+        // but longer than a 100ms one, which makes it two
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(100));
+        assert_eq!(
+            get_boundaries(&detector.push(&samples)),
+            vec![
+                (32, VoiceActivity::SpeechStarted),
+                (64, VoiceActivity::SpeechEnded),
+                (72, VoiceActivity::SpeechStarted),
+                (104, VoiceActivity::SpeechEnded)
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_are_the_same_however_the_samples_are_split() {
+        let (samples, sample_rate) = read_fixture("vad_speech_with_pause.wav");
+
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let whole = detector.push(&samples);
+
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let split: Vec<VoiceActivityFrame> = samples.chunks(37).flat_map(|chunk| detector.push(chunk)).collect();
+
+        assert_eq!(whole.len(), split.len());
+        for (whole, split) in whole.iter().zip(&split) {
+            assert_eq!(whole.activity, split.activity);
+            assert_eq!(whole.samples, split.samples);
+        }
+    }
+
+    #[test]
+    fn noise_floor_adapts_to_steady_background_noise() {
+        // 1s of background noise just under the minimum speech level, then a quiet voice over it from 1s to 1.6s
+        let (samples, sample_rate) = read_fixture("vad_steady_noise.wav");
+        let noise = &samples[..sample_rate as usize];
+        let voice = &samples[sample_rate as usize..sample_rate as usize * 16 / 10];
+
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let frames = detector.push(noise);
+        assert!(frames.iter().all(|frame| frame.activity == VoiceActivity::Silence));
+        let noise_rms = get_rms(noise);
+        assert!((detector.noise_floor - noise_rms).abs() < noise_rms * 0.2);
+
+        // the voice isn't far enough above the noise to count as speech
+        let frames = detector.push(&samples[sample_rate as usize..]);
+        assert!(frames.iter().all(|frame| frame.activity == VoiceActivity::Silence));
+
+        // though without the noise before it, it would have
+        let mut detector = VoiceActivityDetector::new(sample_rate, Duration::from_millis(500));
+        let frames = detector.push(voice);
+        assert_eq!(get_boundaries(&frames), vec![(2, VoiceActivity::SpeechStarted)]);
+    }
+
+    fn get_format_chunk(audio_format: u16, channels: u16, sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+        let block_align = channels * bits_per_sample / 8;
+        let mut chunk = vec![];
+        chunk.extend_from_slice(&audio_format.to_le_bytes());
+        chunk.extend_from_slice(&channels.to_le_bytes());
+        chunk.extend_from_slice(&sample_rate.to_le_bytes());
+        chunk.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        chunk.extend_from_slice(&block_align.to_le_bytes());
+        chunk.extend_from_slice(&bits_per_sample.to_le_bytes());
+        chunk
+    }
+
+    // a WAVE_FORMAT_EXTENSIBLE format chunk, with the real format at the start of the subformat GUID
+    fn get_extensible_format_chunk(audio_format: u16, channels: u16, sample_rate: u32, bits_per_sample: u16) -> Vec<u8> {
+        let mut chunk = get_format_chunk(0xFFFE, channels, sample_rate, bits_per_sample);
+        chunk.extend_from_slice(&22u16.to_le_bytes());
+        chunk.extend_from_slice(&bits_per_sample.to_le_bytes());
+        chunk.extend_from_slice(&0u32.to_le_bytes());
+        chunk.extend_from_slice(&audio_format.to_le_bytes());
+        chunk.extend_from_slice(b"\x00\x00\x00\x00\x10\x00\x80\x00\x00\xAA\x00\x38\x9B\x71");
+        chunk
+    }
+
+    fn make_wav(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (id, chunk) in chunks {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            body.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&body);
+        wav
+    }
+
+    fn make_simple_wav(audio_format: u16, channels: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+        make_wav(&[(b"fmt ", &get_format_chunk(audio_format, channels, 16000, bits_per_sample)), (b"data", data)])
+    }
+
+    #[test]
+    fn decodes_8_bit_pcm() {
+        let wav = make_simple_wav(1, 1, 8, &[0, 128, 192, 255]);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.0, 0.5, 127.0 / 128.0], 16000)));
+    }
+
+    #[test]
+    fn decodes_16_bit_pcm() {
+        let data: Vec<u8> = [i16::MIN, 0, 16384, i16::MAX].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_simple_wav(1, 1, 16, &data);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.0, 0.5, 32767.0 / 32768.0], 16000)));
+    }
+
+    #[test]
+    fn decodes_24_bit_pcm() {
+        let data: Vec<u8> = [-8388608i32, 0, 4194304, -4194304]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes()[..3].to_vec())
+            .collect();
+        let wav = make_simple_wav(1, 1, 24, &data);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.0, 0.5, -0.5], 16000)));
+    }
+
+    #[test]
+    fn decodes_32_bit_pcm() {
+        let data: Vec<u8> = [i32::MIN, 0, 1 << 30, -(1 << 30)].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_simple_wav(1, 1, 32, &data);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.0, 0.5, -0.5], 16000)));
+    }
+
+    #[test]
+    fn decodes_32_bit_float() {
+        let data: Vec<u8> = [-1.0f32, 0.0, 0.25, -0.75].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_simple_wav(3, 1, 32, &data);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.0, 0.25, -0.75], 16000)));
+    }
+
+    #[test]
+    fn decodes_64_bit_float() {
+        let data: Vec<u8> = [-1.0f64, 0.0, 0.25, -0.75].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_simple_wav(3, 1, 64, &data);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.0, 0.25, -0.75], 16000)));
+    }
+
+    #[test]
+    fn decodes_extensible_formats() {
+        let data: Vec<u8> = [i16::MIN, 16384].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_wav(&[(b"fmt ", &get_extensible_format_chunk(1, 1, 44100, 16)), (b"data", &data)]);
+        assert_eq!(decode_wav(&wav), Ok((vec![-1.0, 0.5], 44100)));
+
+        let data: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_wav(&[(b"fmt ", &get_extensible_format_chunk(3, 1, 48000, 32)), (b"data", &data)]);
+        assert_eq!(decode_wav(&wav), Ok((vec![0.25, -0.75], 48000)));
+    }
+
+    #[test]
+    fn mixes_wav_channels_down_to_mono() {
+        let data: Vec<u8> = [16384i16, 0, -16384, -16384].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let wav = make_simple_wav(1, 2, 16, &data);
+        assert_eq!(decode_wav(&wav), Ok((vec![0.25, -0.5], 16000)));
+    }
+
+    #[test]
+    fn skips_other_wav_chunks() {
+        let data: Vec<u8> = [16384i16].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        // an odd sized chunk is followed by a padding byte
+        let wav = make_wav(&[
+            (b"LIST", b"odd"),
+            (b"fmt ", &get_format_chunk(1, 1, 16000, 16)),
+            (b"fact", &[0; 4]),
+            (b"data", &data),
+        ]);
+        assert_eq!(decode_wav(&wav), Ok((vec![0.5], 16000)));
+    }
+
+    #[test]
+    fn decodes_what_there_is_of_a_truncated_data_chunk() {
+        let data: Vec<u8> = [16384i16, -16384].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        let mut wav = make_simple_wav(1, 1, 16, &data);
+        // the data chunk says it's 100 bytes long, and the file ends half way through its third sample
+        let data_size_offset = wav.len() - data.len() - 4;
+        wav[data_size_offset..data_size_offset + 4].copy_from_slice(&100u32.to_le_bytes());
+        wav.push(0x12);
+
+        assert_eq!(decode_wav(&wav), Ok((vec![0.5, -0.5], 16000)));
+    }
+
+    #[test]
+    fn rejects_broken_wav_files() {
+        assert!(decode_wav(b"RIFF\x04\x00\x00\x00AVI ").is_err());
+        assert!(decode_wav(&make_wav(&[(b"data", &[0; 4])])).is_err());
+        assert!(decode_wav(&make_wav(&[(b"fmt ", &get_format_chunk(1, 1, 16000, 16))])).is_err());
+        assert!(decode_wav(&make_wav(&[(b"fmt ", &[1, 0, 1])])).is_err());
+        assert!(decode_wav(&make_simple_wav(1, 0, 16, &[0; 4])).is_err());
+        assert!(decode_wav(&make_simple_wav(1, 1, 12, &[0; 4])).is_err());
+        // ADPCM
+        assert!(decode_wav(&make_simple_wav(2, 1, 4, &[0; 4])).is_err());
+    }
+
+    #[test]
+    fn transcribe_audio_rejects_what_it_cant_decode() {
+        let silence = Duration::from_millis(500);
+        assert!(matches!(transcribe_audio(b"ID3\x04", silence, &[]), Err(MagnusError::AudioFile(_))));
+        assert!(matches!(
+            transcribe_audio(&make_simple_wav(1, 1, 12, &[0; 4]), silence, &[]),
+            Err(MagnusError::AudioFile(_))
+        ));
+        assert!(matches!(transcribe_audio(b"OggS\x00junk", silence, &[]), Err(MagnusError::AudioFile(_))));
+    }
+
+    #[test]
+    fn decodes_ogg_opus_to_mono_at_the_recognizer_rate() {
+        // one second of a 440Hz tone, recorded in stereo at 48kHz
+        let path = format!("{}/tests/fixtures/tone_440hz_stereo.opus", env!("CARGO_MANIFEST_DIR"));
+        let samples = decode_ogg_opus(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(samples.len(), RECOGNIZER_SAMPLE_RATE.0 as usize);
+
+        // Opus is lossy and takes a moment to settle, so only the second half is checked
+        let settled = &samples[samples.len() / 2..];
+        let zero_crossings_per_second = get_zero_crossing_rate(settled) * RECOGNIZER_SAMPLE_RATE.0 as f32;
+        assert!((zero_crossings_per_second - 880.0).abs() < 20.0);
+        let expected_rms = 0.4 * 32767.0 / 2f32.sqrt();
+        assert!((get_rms(settled) - expected_rms).abs() < expected_rms * 0.15);
+    }
+
+    /*
+    These go through the whole pipeline with the bundled vosk-model-small, which cargo test finds since it runs from
+    src-tauri. They need a recording of someone asking "what time is it" at tests/fixtures/speech_what_time_is_it.wav,
+    any format transcribe_audio supports, and are ignored until one is checked in.
+    */
+    fn transcribe_speech_fixture(filler_transcripts: &[String]) -> Option<String> {
+        let path = format!("{}/tests/fixtures/speech_what_time_is_it.wav", env!("CARGO_MANIFEST_DIR"));
+        transcribe_audio(&std::fs::read(&path).unwrap(), Duration::from_millis(500), filler_transcripts).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/speech_what_time_is_it.wav"]
+    fn transcribes_speech_with_the_bundled_model() {
+        assert_eq!(transcribe_speech_fixture(&[]).as_deref(), Some("what time is it"));
+    }
+
+    #[test]
+    #[ignore = "needs tests/fixtures/speech_what_time_is_it.wav"]
+    fn transcription_leaves_out_filler() {
+        assert_eq!(transcribe_speech_fixture(&["what time is it".to_string()]), None);
+    }
+
+    #[test]
+    fn rejects_broken_ogg_files() {
+        assert!(decode_ogg_opus(b"OggS\x00junk").is_err());
+    }
+
+    fn get_tone(hz: f32, sample_rate: u32, length: usize) -> Vec<f32> {
+        (0..length)
+            .map(|i| 0.5 * (2.0 * std::f32::consts::PI * hz * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn resampler_at_the_same_rate_passes_the_audio_through_one_sample_late() {
+        let samples: Vec<f32> = [0.5, -0.25, 0.0, 0.75, -1.0].to_vec();
+        let mut output: Vec<i16> = vec![];
+        Resampler::new(16000, 16000).process(&samples, &mut output);
+        assert_eq!(output, vec![16384, -8192, 0, 24576]);
+    }
+
+    #[test]
+    fn resampler_keeps_its_place_between_buffers() {
+        let samples = get_tone(440.0, 44100, 44100);
+
+        let mut whole: Vec<i16> = vec![];
+        Resampler::new(44100, 16000).process(&samples, &mut whole);
+
+        let mut split: Vec<i16> = vec![];
+        let mut resampler = Resampler::new(44100, 16000);
+        for buffer in samples.chunks(441) {
+            resampler.process(buffer, &mut split);
+        }
+
+        assert_eq!(whole, split);
+        assert!((whole.len() as i64 - 16000).abs() <= 2);
+    }
+
+    #[test]
+    fn resampler_changes_the_number_of_samples() {
+        let mut output: Vec<i16> = vec![];
+        Resampler::new(48000, 16000).process(&get_tone(440.0, 48000, 48000), &mut output);
+        assert!((output.len() as i64 - 16000).abs() <= 2);
+
+        let mut output: Vec<i16> = vec![];
+        Resampler::new(8000, 16000).process(&get_tone(440.0, 8000, 8000), &mut output);
+        assert!((output.len() as i64 - 16000).abs() <= 2);
+    }
+
+    #[test]
+    fn resampler_keeps_speech_frequencies() {
+        let full_rms = 0.5 * 32767.0 / 2f32.sqrt();
+        for hz in [200.0, 1000.0, 4000.0, 6000.0] {
+            let mut output: Vec<i16> = vec![];
+            Resampler::new(48000, 16000).process(&get_tone(hz, 48000, 48000), &mut output);
+            // past the filter's delay
+            let rms = get_rms(&output[100..]);
+            assert!((rms - full_rms).abs() < full_rms * 0.02, "{}Hz came out at {}", hz, rms);
+        }
+    }
+
+    #[test]
+    fn resampler_removes_frequencies_that_would_alias() {
+        // above 8kHz these would fold back into the 16kHz audio as lower tones
+        let full_rms = 0.5 * 32767.0 / 2f32.sqrt();
+        for hz in [10000.0, 12000.0, 15000.0, 20000.0] {
+            let mut output: Vec<i16> = vec![];
+            Resampler::new(48000, 16000).process(&get_tone(hz, 48000, 48000), &mut output);
+            let rms = get_rms(&output[100..]);
+            assert!(rms < full_rms * 0.01, "{}Hz came out at {}", hz, rms);
+        }
+    }
 }
@@ -1,16 +1,20 @@
+use crate::audio_config::{get_stream_config, AudioDirection};
+#[cfg(target_os = "macos")]
+use crate::audio_config::get_preferred_config;
+use crate::error::MagnusError;
 use crate::{assistant, audio_output_device_selection, settings};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{
-    Device, FromSample, Sample, StreamConfig, StreamError
+    BuildStreamError, Device, FromSample, Sample, SampleFormat, SizedSample, Stream, StreamConfig, StreamError
 };
 use crossbeam::channel::{bounded, Receiver, Sender};
 use std::{
     collections::VecDeque,
-    error::Error,
     sync::{Arc, Mutex},
     thread,
     time::Duration,
 };
+use tokio_util::sync::CancellationToken;
 
 pub fn get_default_audio_output_device() -> Device {
     let host = cpal::default_host();
@@ -31,12 +35,11 @@ pub fn get_default_audio_output_device() -> Device {
 }
 
 pub fn get_current_audio_output_device() -> Device {
-    let settings = settings::get_settings().as_object().unwrap().clone();
-    let current_selection = settings.get("audioOutputDeviceSelection").unwrap().as_str().unwrap().to_string();
+    let current_selection = settings::get_settings().audio_output_device_selection;
     let available_devices = get_audio_output_device_list();
 
     for device in available_devices {
-        if device.name().unwrap() == current_selection {
+        if device.name().ok() == current_selection {
             return device
         }
     }
@@ -61,10 +64,11 @@ pub fn get_audio_output_device_list() -> Vec<Device> {
 pub fn run_stream(
     audio_output_receiver: Receiver<Vec<i16>>,
     device: Device,
+    config: StreamConfig,
+    sample_format: SampleFormat,
     synthesizing: Arc<Mutex<bool>>,
-) -> Result<(), Box<dyn Error>> {
-    let format = device.default_output_config().unwrap().sample_format();
-    let mut config: StreamConfig = device.default_output_config().unwrap().into();
+    cancel_token: CancellationToken,
+) -> Result<(), MagnusError> {
     let (error_sender, error_receiver): (Sender<StreamError>, Receiver<StreamError>) = bounded(1);
 
     fn error_callback(e: StreamError, error_sender: Sender<StreamError>) {
@@ -85,57 +89,88 @@ pub fn run_stream(
         }
     }
 
+    // the same stream for every sample format, T is the format's sample type
+    fn build_stream<T>(
+        device: &Device,
+        config: &StreamConfig,
+        audio_output_receiver: Receiver<Vec<i16>>,
+        error_sender: Sender<StreamError>,
+    ) -> Result<Stream, BuildStreamError>
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _| write_audio(data, audio_output_receiver.clone()),
+            move |e| error_callback(e, error_sender.clone()),
+            None,
+        )
+    }
+
     let audio_output_receiver_clone = audio_output_receiver.clone();
 
-    // fix for mac static issue
+    // fix for mac static issue, unless the user picked their own config for this device
     #[cfg(target_os = "macos")]
-    {
-        config = StreamConfig {
+    let config = if get_preferred_config(&device, AudioDirection::Output).is_none() {
+        StreamConfig {
             channels: 2,
             sample_rate: cpal::SampleRate(45000),
             buffer_size: cpal::BufferSize::Default,
-        };
-    }
+        }
+    } else {
+        config
+    };
 
-    let stream = match format {
-        cpal::SampleFormat::F32 => device.build_output_stream(
-            &config.clone().into(),
-            move |data: &mut [f32], _| write_audio(data, audio_output_receiver.clone()),
-            move |e| error_callback(e, error_sender.clone()),
-            None,
-        ),
-        cpal::SampleFormat::I16 => device.build_output_stream(
-            &config.clone().into(),
-            move |data: &mut [i16], _| write_audio(data, audio_output_receiver.clone()),
-            move |e| error_callback(e, error_sender.clone()),
-            None,
-        ),
-        cpal::SampleFormat::U16 => device.build_output_stream(
-            &config.clone().into(),
-            move |data: &mut [u16], _| write_audio(data, audio_output_receiver.clone()),
-            move |e| error_callback(e, error_sender.clone()),
-            None,
-        ),
-        _ => panic!(),
+    let receiver = audio_output_receiver;
+    let stream = match sample_format {
+        SampleFormat::I8 => build_stream::<i8>(&device, &config, receiver, error_sender),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, receiver, error_sender),
+        SampleFormat::I32 => build_stream::<i32>(&device, &config, receiver, error_sender),
+        SampleFormat::I64 => build_stream::<i64>(&device, &config, receiver, error_sender),
+        SampleFormat::U8 => build_stream::<u8>(&device, &config, receiver, error_sender),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, receiver, error_sender),
+        SampleFormat::U32 => build_stream::<u32>(&device, &config, receiver, error_sender),
+        SampleFormat::U64 => build_stream::<u64>(&device, &config, receiver, error_sender),
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, receiver, error_sender),
+        SampleFormat::F64 => build_stream::<f64>(&device, &config, receiver, error_sender),
+        // cpal may add formats in the future
+        sample_format => {
+            return Err(MagnusError::AudioDevice(format!(
+                "unsupported output sample format {}",
+                sample_format
+            )))
+        }
     }
-    .expect("Failed to build audio output stream!");
+    .map_err(|err| MagnusError::AudioDevice(format!("failed to build audio output stream: {}", err)))?;
 
     match stream.play() {
         Ok(_) => println!("Successfully started audio output stream!"),
-        Err(error) => println!("Failed to start audio output stream: {}", error),
+        Err(error) => {
+            return Err(MagnusError::AudioDevice(format!(
+                "failed to start audio output stream: {}",
+                error
+            )))
+        }
     }
 
     loop {
         if let Ok(stream_error) = error_receiver.try_recv() {
             drop(stream);
-            return Err(Box::new(stream_error));
+            return Err(MagnusError::AudioDevice(stream_error.to_string()));
         } else if !*synthesizing.lock().unwrap() && audio_output_receiver_clone.is_empty() {
             return Ok(());
+        } else if cancel_token.is_cancelled() {
+            // dropping the stream stops playback mid sentence
+            println!("Speech cancelled, exiting output stream.");
+            return Ok(());
         }
     }
 }
 
-pub async fn speak(assistant_message: String) -> Result<(), Box<dyn Error>> {
+pub async fn speak(
+    assistant_message: String,
+    cancel_token: CancellationToken,
+) -> Result<(), MagnusError> {
     // create speech sender and receiver
     let (audio_output_sender, audio_output_receiver): (Sender<Vec<i16>>, Receiver<Vec<i16>>) =
         crossbeam::channel::unbounded::<Vec<i16>>();
@@ -143,34 +178,44 @@ pub async fn speak(assistant_message: String) -> Result<(), Box<dyn Error>> {
 
     // find an output device
     let audio_output_device = get_current_audio_output_device();
-    let audio_output_config = audio_output_device.default_output_config().unwrap();
+    let (audio_output_config, sample_format) = get_stream_config(&audio_output_device, AudioDirection::Output)?;
+    let (sample_rate, channels) = (audio_output_config.sample_rate, audio_output_config.channels);
 
     // spawn create_speech with sender
     *synthesizing.lock().unwrap() = true;
+    let speech_cancel_token = cancel_token.clone();
     let create_speech_handle = tauri::async_runtime::spawn(async move {
-        assistant::create_speech(
-            assistant_message,
-            audio_output_sender,
-            audio_output_config.sample_rate(),
-            audio_output_config.channels(),
-        )
-        .await
+        tokio::select! {
+            result = assistant::create_speech(
+                assistant_message,
+                audio_output_sender,
+                sample_rate,
+                channels,
+            ) => result,
+            _ = speech_cancel_token.cancelled() => Ok(()),
+        }
     });
 
     // spawn output with receiver
     let synthesizing_clone = synthesizing.clone();
     let output_stream_handle = thread::spawn(move || {
-        let _ = run_stream(
+        run_stream(
             audio_output_receiver,
             audio_output_device,
+            audio_output_config,
+            sample_format,
             synthesizing_clone,
-        );
+            cancel_token,
+        )
     });
 
     // wait for the threads to finish
-    let _ = create_speech_handle.await;
+    let speech_result = create_speech_handle.await;
     *synthesizing.lock().unwrap() = false;
-    output_stream_handle.join().unwrap();
+    output_stream_handle.join().unwrap()?;
 
-    return Ok(());
+    match speech_result {
+        Ok(result) => result,
+        Err(err) => Err(MagnusError::AudioDevice(format!("speech task failed: {}", err))),
+    }
 }
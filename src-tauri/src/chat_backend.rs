@@ -0,0 +1,678 @@
+use crate::assistant::execute;
+use crate::error::{check_response, MagnusError};
+use crate::globals::{self, get_magnus_id, get_open_ai_key, get_reqwest_client, get_thread_id};
+use crate::request::{get_stream_idle_timeout, get_stream_timeout_error, send_stream_with_retry, send_with_retry};
+use crate::settings::{get_api_base_url, get_settings};
+use crate::tools::{get_invalid_args_output, get_tool_registry};
+use crate::{Payload, APP_HANDLE};
+use futures::future::join_all;
+use futures::stream::{BoxStream, StreamExt};
+use reqwest::Response;
+use serde_json::{Map, Value};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tauri::Manager;
+use tokio_util::sync::CancellationToken;
+
+/*
+A ChatBackend is anything that can take the user's message and come back with Magnus' response. The rest of the app
+only talks to the model through this trait, so switching providers is a matter of changing "chatBackend" in settings.
+
+Trait objects can't have async fns, so just like AsyncAction in tools.rs each method returns its Future pinned in a Box.
+*/
+pub type BackendFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait ChatBackend: Send + Sync {
+    // starts a fresh conversation, dropping any context from the previous one
+    fn new_conversation(&self) -> BackendFuture<'_, Result<(), MagnusError>>;
+
+    // adds the user's message to the conversation and returns Magnus' response, running any tools it asks for.
+    // returns None if the turn was cancelled before Magnus finished responding
+    fn send_message(
+        &self,
+        user_message: String,
+        cancel_token: CancellationToken,
+    ) -> BackendFuture<'_, Result<Option<String>, MagnusError>>;
+}
+
+// the Assistants API keeps this on the remote assistant, Chat Completions needs it sent with every request
+const SYSTEM_PROMPT: &str = "You are Magnus, a friendly and helpful desktop assistant. Your responses are often read \
+aloud to the user, so keep them brief and conversational unless the user asks for detail or for code.";
+
+// how many of the most recent messages the model gets to see
+fn get_n_messages_in_context() -> usize {
+    if globals::get_is_signed_in() {
+        5
+    } else {
+        2
+    }
+}
+
+const TOOL_ROUNDS_EXHAUSTED_MESSAGE: &str =
+    "Sorry, I couldn't finish that. It needed more steps than I'm allowed to take for one question.";
+
+// how many times the model may ask for tools in one turn, configurable through "maxToolRounds" in settings.json.
+// without a limit a model that keeps asking for tools would never answer
+fn get_max_tool_rounds() -> u64 {
+    get_settings().max_tool_rounds
+}
+
+// what Magnus says when it runs out of tool rounds, keeping anything it already told the user
+fn get_tool_rounds_exhausted_response(partial_response: &str) -> String {
+    if partial_response.trim().is_empty() {
+        TOOL_ROUNDS_EXHAUSTED_MESSAGE.to_string()
+    } else {
+        format!("{}\n\n{}", partial_response.trim_end(), TOOL_ROUNDS_EXHAUSTED_MESSAGE)
+    }
+}
+
+// picks the backend named by "chatBackend" in settings.json, defaulting to the OpenAI Assistants API
+pub fn get_chat_backend() -> Box<dyn ChatBackend> {
+    let settings = get_settings();
+    match settings.chat_backend.as_str() {
+        "chatCompletions" => Box::new(ChatCompletionsBackend {
+            base_url: get_api_base_url(),
+            model: settings.chat_model,
+        }),
+        _ => Box::new(AssistantsBackend {
+            base_url: get_api_base_url(),
+        }),
+    }
+}
+
+// OpenAI Assistants API, the conversation lives in a remote thread and the assistant is configured remotely
+pub struct AssistantsBackend {
+    pub base_url: String,
+}
+
+impl AssistantsBackend {
+    async fn create_message_thread(&self) -> Result<String, MagnusError> {
+        let response = send_with_retry(
+            get_reqwest_client()
+                .post(format!("{}/threads", self.base_url))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .header("OpenAI-Beta", "assistants=v2"),
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        let thread = response.json::<Value>().await?;
+
+        match thread["id"].as_str() {
+            Some(thread_id) => Ok(thread_id.to_string()),
+            None => Err(MagnusError::MalformedResponse(
+                "no id for the new thread".to_string(),
+            )),
+        }
+    }
+
+    async fn create_message(&self, user_message: Value, thread_id: String) -> Result<(), MagnusError> {
+        let response = send_with_retry(
+            get_reqwest_client()
+                .post(format!("{}/threads/{}/messages", self.base_url, thread_id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .header("OpenAI-Beta", "assistants=v2")
+                .json(&user_message),
+        )
+        .await?;
+        check_response(response).await?;
+
+        Ok(())
+    }
+
+    // starts a streamed run, returning its id along with the rest of its events to be read by run_and_wait
+    async fn create_run(&self, thread_id: String) -> Result<(String, EventStream), MagnusError> {
+        let data = serde_json::json!({
+            "assistant_id": get_magnus_id(),
+            "tools": get_tool_registry().get_definitions(),
+            "stream": true,
+            "truncation_strategy": {
+                "type": "last_messages",
+                "last_messages": get_n_messages_in_context()
+            }
+        });
+
+        let response = send_stream_with_retry(
+            get_reqwest_client()
+                .post(format!("{}/threads/{}/runs", self.base_url, thread_id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .header("OpenAI-Beta", "assistants=v2")
+                .json(&data),
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        let mut events = EventStream::new(response);
+        let mut run_id = String::new();
+
+        // the first event of a run holds the run object
+        while let Some(event) = events.next_event().await? {
+            if event.event == "thread.run.created" {
+                let run = serde_json::from_str::<Value>(&event.data).unwrap_or_default();
+                run_id = run["id"].as_str().unwrap_or_default().to_string();
+                break;
+            }
+        }
+
+        if run_id.is_empty() {
+            return Err(MagnusError::MalformedResponse(
+                "the run was never created".to_string(),
+            ));
+        }
+
+        Ok((run_id, events))
+    }
+
+    async fn cancel_run(&self, run_id: &str, thread_id: String) -> Result<(), MagnusError> {
+        let response = send_with_retry(
+            get_reqwest_client()
+                .post(format!(
+                    "{}/threads/{}/runs/{}/cancel",
+                    self.base_url, thread_id, run_id
+                ))
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .header("OpenAI-Beta", "assistants=v2"),
+        )
+        .await?;
+        check_response(response).await?;
+
+        println!("Cancelled run: {}", run_id);
+        Ok(())
+    }
+
+    // follows the run's events until it is done, emitting text as it arrives and returning the full message
+    async fn run_and_wait(
+        &self,
+        run_id: &str,
+        mut events: EventStream,
+        thread_id: String,
+    ) -> Result<String, MagnusError> {
+        let mut assistant_response = String::new();
+        let mut tool_rounds: u64 = 0;
+
+        while let Some(event) = events.next_event().await? {
+            if event.data == "[DONE]" {
+                break;
+            }
+
+            let data = serde_json::from_str::<Value>(&event.data).unwrap_or_default();
+
+            match event.event.as_str() {
+                "thread.message.delta" => {
+                    if let Some(content) = data["delta"]["content"].as_array() {
+                        for part in content {
+                            if let Some(delta) = part["text"]["value"].as_str() {
+                                assistant_response.push_str(delta);
+                                emit_delta(delta);
+                            }
+                        }
+                    }
+                }
+                "thread.run.requires_action" => {
+                    if data["required_action"]["type"] == "submit_tool_outputs" {
+                        // the run can't be told to stop using tools, so it is cancelled instead
+                        if tool_rounds >= get_max_tool_rounds() {
+                            println!("Run {} used all of its tool rounds", run_id);
+                            self.cancel_run(run_id, thread_id.clone()).await?;
+                            return Ok(get_tool_rounds_exhausted_response(&assistant_response));
+                        }
+                        tool_rounds += 1;
+
+                        let tool_calls = data["required_action"]["submit_tool_outputs"]["tool_calls"]
+                            .as_array()
+                            .cloned()
+                            .unwrap_or_default();
+
+                        let tool_outputs: Vec<Value> = run_tool_calls(&tool_calls)
+                            .await
+                            .into_iter()
+                            .map(|(tool_call_id, output)| {
+                                serde_json::json!({
+                                    "tool_call_id": tool_call_id,
+                                    "output": output
+                                })
+                            })
+                            .collect();
+
+                        // the rest of the run continues on the stream returned by the submission
+                        events = self
+                            .submit_tool_outputs(
+                                run_id,
+                                thread_id.clone(),
+                                serde_json::json!({"tool_outputs": tool_outputs, "stream": true}),
+                            )
+                            .await?;
+                    }
+                }
+                "thread.run.completed" => break,
+                "thread.run.incomplete" => {
+                    // the run hit a token limit, whatever it managed to say is still worth showing
+                    if assistant_response.is_empty() {
+                        return Err(get_run_error(&data));
+                    }
+                    println!("Run incomplete: {}", data["incomplete_details"]["reason"]);
+                    break;
+                }
+                "thread.run.failed" | "thread.run.cancelled" | "thread.run.expired" => {
+                    return Err(get_run_error(&data));
+                }
+                "error" => {
+                    return Err(MagnusError::RunFailed(
+                        data["message"].as_str().unwrap_or("unknown error").to_string(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        // not every compatible server streams message deltas, fall back to reading the thread
+        if assistant_response.is_empty() {
+            return self.get_assistant_last_response(thread_id).await;
+        }
+
+        Ok(assistant_response)
+    }
+
+    async fn submit_tool_outputs(
+        &self,
+        run_id: &str,
+        thread_id: String,
+        tool_outputs: Value,
+    ) -> Result<EventStream, MagnusError> {
+        let response = send_stream_with_retry(
+            get_reqwest_client()
+                .post(format!(
+                    "{}/threads/{}/runs/{}/submit_tool_outputs",
+                    self.base_url, thread_id, run_id
+                ))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .header("OpenAI-Beta", "assistants=v2")
+                .json(&tool_outputs),
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        Ok(EventStream::new(response))
+    }
+
+    async fn get_assistant_last_response(&self, thread_id: String) -> Result<String, MagnusError> {
+        let response = send_with_retry(
+            get_reqwest_client()
+                .get(format!("{}/threads/{}/messages", self.base_url, thread_id))
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .header("OpenAI-Beta", "assistants=v2"),
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        let messages = response.json::<Value>().await?;
+
+        match messages["data"][0]["content"][0]["text"]["value"].as_str() {
+            Some(assistant_response) => Ok(assistant_response.to_string()),
+            None => Err(MagnusError::MalformedResponse(
+                "no text in the assistant's last message".to_string(),
+            )),
+        }
+    }
+}
+
+impl ChatBackend for AssistantsBackend {
+    fn new_conversation(&self) -> BackendFuture<'_, Result<(), MagnusError>> {
+        Box::pin(async move {
+            let thread_id = self.create_message_thread().await?;
+            globals::set_thread_id(thread_id);
+            println!("Successfully created thread: {}", get_thread_id());
+            Ok(())
+        })
+    }
+
+    fn send_message(
+        &self,
+        user_message: String,
+        cancel_token: CancellationToken,
+    ) -> BackendFuture<'_, Result<Option<String>, MagnusError>> {
+        Box::pin(async move {
+            // set once the run exists, it has to be cancelled on the server as well
+            let created_run_id: Mutex<Option<String>> = Mutex::new(None);
+
+            let turn = async {
+                // the thread is created on the first message so the app can start without a connection
+                if get_thread_id().is_empty() {
+                    self.new_conversation().await?;
+                }
+
+                let message = serde_json::json!({
+                    "role": "user",
+                    "content": user_message
+                });
+
+                self.create_message(message, get_thread_id()).await?;
+
+                let (run_id, events) = self.create_run(get_thread_id()).await?;
+                *created_run_id.lock().unwrap() = Some(run_id.clone());
+
+                let response = self.run_and_wait(&run_id, events, get_thread_id()).await?;
+                Ok::<String, MagnusError>(response)
+            };
+
+            // dropping the turn future stops whichever request or tool it's waiting on, so nothing more is posted to
+            // the thread once a new turn can start
+            tokio::select! {
+                response = turn => response.map(Some),
+                _ = cancel_token.cancelled() => {
+                    let run_id = created_run_id.lock().unwrap().take();
+                    if let Some(run_id) = run_id {
+                        // the turn was cancelled either way, so this isn't worth showing the user
+                        if let Err(err) = self.cancel_run(&run_id, get_thread_id()).await {
+                            println!("Error cancelling run {}: {}", run_id, err);
+                        }
+                    }
+                    Ok(None)
+                }
+            }
+        })
+    }
+}
+
+// OpenAI-style Chat Completions API, the conversation history is kept locally and sent with every request
+pub struct ChatCompletionsBackend {
+    pub base_url: String,
+    pub model: String,
+}
+
+impl ChatCompletionsBackend {
+    // streams the model's next message, emitting text as it arrives. the message either has content or tool calls.
+    // when allow_tools is false the model is told to answer with what it already has
+    async fn create_completion(&self, messages: &[Value], allow_tools: bool) -> Result<Value, MagnusError> {
+        let data = serde_json::json!({
+            "model": self.model,
+            "messages": messages,
+            "tools": get_tool_registry().get_definitions(),
+            "tool_choice": if allow_tools { "auto" } else { "none" },
+            "stream": true
+        });
+
+        let response = send_stream_with_retry(
+            get_reqwest_client()
+                .post(format!("{}/chat/completions", self.base_url))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", get_open_ai_key()))
+                .json(&data),
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        let mut events = EventStream::new(response);
+        let mut content = String::new();
+        let mut tool_calls: Vec<Value> = vec![];
+
+        while let Some(event) = events.next_event().await? {
+            if event.data == "[DONE]" {
+                break;
+            }
+
+            let chunk = serde_json::from_str::<Value>(&event.data).unwrap_or_default();
+            let delta = &chunk["choices"][0]["delta"];
+
+            if let Some(text) = delta["content"].as_str() {
+                content.push_str(text);
+                emit_delta(text);
+            }
+
+            // tool calls arrive in pieces, the first piece of each has the id and name and the rest add arguments
+            if let Some(tool_call_deltas) = delta["tool_calls"].as_array() {
+                for tool_call_delta in tool_call_deltas {
+                    let index = tool_call_delta["index"].as_u64().unwrap_or_default() as usize;
+                    while tool_calls.len() <= index {
+                        tool_calls.push(serde_json::json!({
+                            "id": "",
+                            "type": "function",
+                            "function": { "name": "", "arguments": "" }
+                        }));
+                    }
+
+                    let tool_call = &mut tool_calls[index];
+                    if let Some(id) = tool_call_delta["id"].as_str() {
+                        tool_call["id"] = Value::from(id);
+                    }
+                    for key in ["name", "arguments"] {
+                        if let Some(piece) = tool_call_delta["function"][key].as_str() {
+                            let joined = format!("{}{}", tool_call["function"][key].as_str().unwrap_or_default(), piece);
+                            tool_call["function"][key] = Value::from(joined);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut message = serde_json::json!({
+            "role": "assistant",
+            "content": content
+        });
+        if !tool_calls.is_empty() {
+            message["tool_calls"] = Value::from(tool_calls);
+        }
+
+        Ok(message)
+    }
+
+    // requests completions until the model stops asking for tools, returning its final message
+    async fn complete_turn(&self, user_message: String) -> Result<String, MagnusError> {
+        let mut history = globals::get_chat_history();
+        let turn_start = history.len();
+        history.push(serde_json::json!({
+            "role": "user",
+            "content": user_message
+        }));
+
+        // same truncation the Assistants backend asks for, but a tool result can't be separated from its call
+        let mut first_in_context =
+            turn_start.saturating_sub(get_n_messages_in_context().saturating_sub(1));
+        while first_in_context > 0 && history[first_in_context]["role"] == "tool" {
+            first_in_context -= 1;
+        }
+
+        let max_tool_rounds = get_max_tool_rounds();
+        let mut tool_rounds: u64 = 0;
+
+        loop {
+            // the system prompt always goes first
+            let mut messages = vec![serde_json::json!({
+                "role": "system",
+                "content": SYSTEM_PROMPT
+            })];
+            messages.extend_from_slice(&history[first_in_context..]);
+
+            // once the tool rounds are used up the model has to answer with the results it already has
+            let allow_tools = tool_rounds < max_tool_rounds;
+            let message = self.create_completion(&messages, allow_tools).await?;
+
+            match message["tool_calls"].as_array() {
+                Some(tool_calls) if allow_tools => {
+                    tool_rounds += 1;
+                    let tool_outputs = run_tool_calls(tool_calls).await;
+                    history.push(message.clone());
+                    for (tool_call_id, output) in tool_outputs {
+                        history.push(serde_json::json!({
+                            "role": "tool",
+                            "tool_call_id": tool_call_id,
+                            "content": output
+                        }));
+                    }
+                }
+                // some servers ignore tool_choice, their tool calls are dropped rather than run
+                Some(_) => {
+                    println!("Model asked for tools after using all of its tool rounds");
+                    let assistant_response = get_tool_rounds_exhausted_response(
+                        message["content"].as_str().unwrap_or_default(),
+                    );
+                    history.push(serde_json::json!({
+                        "role": "assistant",
+                        "content": assistant_response
+                    }));
+                    globals::set_chat_history(history);
+
+                    return Ok(assistant_response);
+                }
+                None => {
+                    let assistant_response = message["content"].as_str().unwrap_or_default().to_string();
+                    history.push(message);
+                    globals::set_chat_history(history);
+
+                    return Ok(assistant_response);
+                }
+            }
+        }
+    }
+}
+
+impl ChatBackend for ChatCompletionsBackend {
+    fn new_conversation(&self) -> BackendFuture<'_, Result<(), MagnusError>> {
+        Box::pin(async move {
+            globals::set_chat_history(vec![]);
+            Ok(())
+        })
+    }
+
+    fn send_message(
+        &self,
+        user_message: String,
+        cancel_token: CancellationToken,
+    ) -> BackendFuture<'_, Result<Option<String>, MagnusError>> {
+        Box::pin(async move {
+            // a cancelled turn is left out of the history entirely
+            tokio::select! {
+                response = self.complete_turn(user_message) => response.map(Some),
+                _ = cancel_token.cancelled() => Ok(None),
+            }
+        })
+    }
+}
+
+// describes why a run ended without completing
+fn get_run_error(run: &Value) -> MagnusError {
+    let status = run["status"].as_str().unwrap_or("unknown");
+
+    if run["last_error"]["code"] == "rate_limit_exceeded" {
+        return MagnusError::RateLimit(run["last_error"]["message"].to_string());
+    }
+
+    let reason = match run["last_error"]["message"].as_str() {
+        Some(message) => message.to_string(),
+        None => run["incomplete_details"]["reason"]
+            .as_str()
+            .unwrap_or("no reason given")
+            .to_string(),
+    };
+
+    MagnusError::RunFailed(format!("run {}: {}", status, reason))
+}
+
+// runs every tool call the model asked for at the same time, returning the id of each call along with its output in
+// the order the calls were made
+async fn run_tool_calls(tool_calls: &[Value]) -> Vec<(Value, String)> {
+    let runs = tool_calls.iter().map(|tool_call| async move {
+        let tool = tool_call["function"]["name"].as_str().unwrap_or_default();
+        let arguments = tool_call["function"]["arguments"].as_str().unwrap_or("{}");
+        let start_time = Instant::now();
+
+        let tool_output = match serde_json::from_str::<Map<String, Value>>(arguments) {
+            // the model is told when a tool fails so it can pass that on or try something else
+            Ok(args) => match execute(tool, args).await {
+                Ok(output) => output,
+                Err(err) => format!("You MUST tell the user about this error: {}", err),
+            },
+            Err(err) => get_invalid_args_output(
+                tool,
+                &[format!("arguments are not a valid JSON object: {}", err)],
+            ),
+        };
+
+        println!("{} tool took {}ms", tool, start_time.elapsed().as_millis());
+        (tool_call["id"].clone(), tool_output)
+    });
+
+    join_all(runs).await
+}
+
+// sends a piece of Magnus' response to the frontend as soon as it is received
+fn emit_delta(delta: &str) {
+    if let Some(app_handle) = APP_HANDLE.lock().unwrap().as_ref() {
+        let _ = app_handle.emit_all(
+            "magnus-delta",
+            Payload {
+                message: delta.to_string(),
+            },
+        );
+    }
+}
+
+// a single server-sent event, `event` is empty for streams that only send data lines
+struct ServerSentEvent {
+    event: String,
+    data: String,
+}
+
+// reads the server-sent events of a streamed response as they arrive
+struct EventStream {
+    bytes: BoxStream<'static, Result<Vec<u8>, MagnusError>>,
+    idle_timeout: Duration, // how long to wait for the next chunk before giving up on the response
+    buffer: Vec<u8>,
+    event: String,
+    data: Vec<String>,
+}
+
+impl EventStream {
+    fn new(response: Response) -> Self {
+        EventStream {
+            bytes: response
+                .bytes_stream()
+                .map(|chunk| chunk.map(|bytes| bytes.to_vec()).map_err(MagnusError::from))
+                .boxed(),
+            idle_timeout: get_stream_idle_timeout(),
+            buffer: vec![],
+            event: String::new(),
+            data: vec![],
+        }
+    }
+
+    // returns the next complete event, or None once the response has ended
+    async fn next_event(&mut self) -> Result<Option<ServerSentEvent>, MagnusError> {
+        loop {
+            // an event is a group of "field: value" lines ended by a blank line
+            while let Some(newline) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line_bytes: Vec<u8> = self.buffer.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line_bytes);
+                let line = line.trim_end_matches(|c| c == '\n' || c == '\r');
+
+                if line.is_empty() {
+                    if !self.data.is_empty() {
+                        return Ok(Some(ServerSentEvent {
+                            event: std::mem::take(&mut self.event),
+                            data: std::mem::take(&mut self.data).join("\n"),
+                        }));
+                    }
+                    self.event.clear();
+                } else if let Some(event) = line.strip_prefix("event:") {
+                    self.event = event.trim().to_string();
+                } else if let Some(data) = line.strip_prefix("data:") {
+                    self.data.push(data.trim_start().to_string());
+                }
+            }
+
+            match tokio::time::timeout(self.idle_timeout, self.bytes.next()).await {
+                Ok(Some(chunk)) => self.buffer.extend(chunk?),
+                Ok(None) => return Ok(None),
+                Err(_) => return Err(get_stream_timeout_error(self.idle_timeout)),
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::settings::{get_settings, Permission};
+use crate::APP_HANDLE;
+use lazy_static::lazy_static;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::Manager;
+use tokio::sync::oneshot;
+
+/*
+Tools whose permissions are set to "ask" in permissions.json have to be approved by the user every time they're used.
+
+request_confirmation sends the frontend a "confirm-action" event and waits for the user to answer with the
+approve_action or deny_action command. Each request has its own id since tool calls run concurrently, so more than one
+can be waiting at once. Not answering in time counts as denying, and once a request is over for any reason the frontend
+gets a "confirm-action-closed" event so it can take the prompt down.
+*/
+lazy_static! {
+    // the requests the user hasn't answered yet, by id
+    static ref PENDING_CONFIRMATIONS: Mutex<HashMap<u64, oneshot::Sender<bool>>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_CONFIRMATION_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, serde::Serialize)]
+struct ConfirmationRequest {
+    id: u64,
+    tool: String,
+    description: String,
+    arguments: Map<String, Value>,
+    permissions: Vec<String>, // the permissions set to "ask" that this tool needs
+}
+
+#[derive(Clone, serde::Serialize)]
+struct ConfirmationClosed {
+    id: u64,
+}
+
+// how long to wait for the user to answer, configurable through "confirmationTimeoutSeconds" in settings.json
+fn get_confirmation_timeout() -> Duration {
+    Duration::from_secs(get_settings().confirmation_timeout_seconds)
+}
+
+// removes a request once it is over, including when the turn is cancelled while it's waiting
+struct PendingConfirmation {
+    id: u64,
+}
+
+impl Drop for PendingConfirmation {
+    fn drop(&mut self) {
+        PENDING_CONFIRMATIONS.lock().unwrap().remove(&self.id);
+
+        if let Some(app_handle) = APP_HANDLE.lock().unwrap().as_ref() {
+            let _ = app_handle.emit_all("confirm-action-closed", ConfirmationClosed { id: self.id });
+        }
+    }
+}
+
+// asks the user whether a tool may run with these arguments, returning true only if they approve it
+pub async fn request_confirmation(
+    tool: &str,
+    description: &str,
+    arguments: &Map<String, Value>,
+    permissions: &[Permission],
+) -> bool {
+    let id = NEXT_CONFIRMATION_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = oneshot::channel();
+    PENDING_CONFIRMATIONS.lock().unwrap().insert(id, sender);
+    let _pending = PendingConfirmation { id };
+
+    // without a window there's nobody to ask
+    let app_handle = APP_HANDLE.lock().unwrap().clone();
+    let app_handle = match app_handle {
+        Some(app_handle) => app_handle,
+        None => return false,
+    };
+    let _ = app_handle.emit_all(
+        "confirm-action",
+        ConfirmationRequest {
+            id,
+            tool: tool.to_string(),
+            description: description.to_string(),
+            arguments: arguments.clone(),
+            permissions: permissions.iter().map(|p| p.as_str().to_string()).collect(),
+        },
+    );
+    println!("waiting for the user to confirm {}", tool);
+
+    match tokio::time::timeout(get_confirmation_timeout(), receiver).await {
+        Ok(Ok(approved)) => approved,
+        _ => {
+            println!("no answer to confirm {}", tool);
+            false
+        }
+    }
+}
+
+// answers a request, returning false if it was already answered or is no longer waiting
+pub fn resolve_confirmation(id: u64, approved: bool) -> bool {
+    match PENDING_CONFIRMATIONS.lock().unwrap().remove(&id) {
+        Some(sender) => sender.send(approved).is_ok(),
+        None => false,
+    }
+}
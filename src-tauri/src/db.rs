@@ -1,4 +1,5 @@
 use crate::globals::{get_auth_jwt, get_auth_user_id, get_reqwest_client};
+use crate::request::send_with_retry;
 use lazy_static::lazy_static;
 use std::env;
 
@@ -46,13 +47,14 @@ impl User {
 
         // send request to create user.
         // we are doing all input validation on the backend
-        let response = get_reqwest_client()
-            .post(url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("{} {}", "Bearer ", get_auth_jwt()))
-            .json(&user)
-            .send()
-            .await?;
+        let response = send_with_retry(
+            get_reqwest_client()
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("{} {}", "Bearer ", get_auth_jwt()))
+                .json(&user),
+        )
+        .await?;
 
         // if the result was something other than success of already exists then log
         if response.status().as_u16() != 200 && response.status().as_u16() != 409 {
@@ -110,13 +112,14 @@ impl Log {
 
         // send request to create a log.
         // we are doing all input validation on the backend
-        let _ = get_reqwest_client()
-            .post(url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("{} {}", "Bearer ", get_auth_jwt()))
-            .json(&log)
-            .send()
-            .await?;
+        let _ = send_with_retry(
+            get_reqwest_client()
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("{} {}", "Bearer ", get_auth_jwt()))
+                .json(&log),
+        )
+        .await?;
         Ok(())
     }
 }
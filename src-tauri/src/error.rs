@@ -0,0 +1,98 @@
+use reqwest::{Response, StatusCode};
+use std::fmt;
+
+/*
+Everything that can go wrong during a conversation turn. These are passed back up to run_conversation_flow instead of
+panicking, which then shows the user the Display message in an "error" event, so that message should make sense to
+someone who has never seen the code.
+*/
+#[derive(Debug, Clone)]
+pub enum MagnusError {
+    Network(String), // the request never got a response, or got an unexpected status back
+    Auth(String), // the API rejected our key
+    RateLimit(String), // the API asked us to slow down
+    MalformedResponse(String), // the response was missing something we needed from it
+    UnknownTool(String), // the model asked for a tool that doesn't exist
+    PermissionDenied(Vec<String>), // the names of the Permissions that have not been granted
+    ActionDenied(String), // the user said no when asked to confirm a tool, or didn't answer in time
+    ToolTimedOut(String, u64), // a tool was stopped after running for this many seconds
+    ToolFailed(String, String), // a tool couldn't do what it was asked, and why
+    AudioDevice(String), // an input or output device couldn't be found, configured or started
+    AudioFile(String), // an audio file couldn't be read or decoded
+    RunFailed(String), // the model stopped before it finished responding
+}
+
+impl fmt::Display for MagnusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MagnusError::Network(details) => write!(f, "A network request failed ({})", details),
+            MagnusError::Auth(details) => {
+                write!(f, "A service didn't accept Magnus' credentials ({})", details)
+            }
+            MagnusError::RateLimit(details) => write!(
+                f,
+                "A service is receiving too many requests, try again in a moment ({})",
+                details
+            ),
+            MagnusError::MalformedResponse(details) => write!(
+                f,
+                "A service sent back a response that didn't make sense ({})",
+                details
+            ),
+            MagnusError::UnknownTool(tool) => write!(f, "There is no tool named {}", tool),
+            MagnusError::PermissionDenied(permissions) => write!(
+                f,
+                "Access to the following features needs to be allowed in settings: {}",
+                permissions.join(", ")
+            ),
+            MagnusError::ActionDenied(tool) => {
+                write!(f, "The user didn't allow Magnus to use the {} tool", tool)
+            }
+            MagnusError::ToolTimedOut(tool, seconds) => write!(
+                f,
+                "The {} tool took longer than {} seconds and was stopped",
+                tool, seconds
+            ),
+            MagnusError::ToolFailed(tool, details) => write!(f, "The {} tool failed ({})", tool, details),
+            MagnusError::AudioDevice(details) => {
+                write!(f, "There was a problem with the audio device ({})", details)
+            }
+            MagnusError::AudioFile(details) => {
+                write!(f, "The audio file couldn't be transcribed ({})", details)
+            }
+            MagnusError::RunFailed(details) => write!(
+                f,
+                "The assistant stopped before it finished responding ({})",
+                details
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MagnusError {}
+
+impl From<reqwest::Error> for MagnusError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_decode() {
+            MagnusError::MalformedResponse(error.to_string())
+        } else {
+            MagnusError::Network(error.to_string())
+        }
+    }
+}
+
+// turns an unsuccessful response into the matching error, passing successful responses through untouched
+pub async fn check_response(response: Response) -> Result<Response, MagnusError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let details = format!("{}: {}", status, response.text().await.unwrap_or_default());
+
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(MagnusError::Auth(details)),
+        StatusCode::TOO_MANY_REQUESTS => Err(MagnusError::RateLimit(details)),
+        _ => Err(MagnusError::Network(details)),
+    }
+}
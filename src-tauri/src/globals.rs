@@ -1,14 +1,28 @@
 use lazy_static::lazy_static;
 use reqwest::Client;
+use crate::settings::PermissionGrant;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
 use std::sync::Mutex;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 use vosk::Model;
 
 lazy_static! {
-    static ref REQWEST_CLIENT: Client = Client::new();
+    // request timeouts and retries are handled per request in request.rs
+    static ref REQWEST_CLIENT: Client = Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
 
     static ref THREAD_ID: Mutex<String> = Mutex::new("".to_string());
 
+    static ref CHAT_HISTORY: Mutex<Vec<Value>> = Mutex::new(vec![]);
+
+    // cancels the conversation turn that is currently running
+    static ref CANCEL_TOKEN: Mutex<CancellationToken> = Mutex::new(CancellationToken::new());
+
     static ref VOSK_MODEL: Model = {
         // let model_path = "./models/vosk-model-en-us-0.42-gigaspeech/";
 
@@ -89,6 +103,24 @@ lazy_static! {
 
     static ref IS_SIGNED_IN: Mutex<bool> = Mutex::new(false);
 
+    // whether the last request to the chat backend got through, assumed true until one fails
+    static ref IS_BACKEND_AVAILABLE: Mutex<bool> = Mutex::new(true);
+
+    // Magnus' last answer, for the hotkey that repeats it
+    static ref LAST_ANSWER: Mutex<String> = Mutex::new("".to_string());
+
+    static ref IS_TTS_MUTED: Mutex<bool> = Mutex::new(false);
+
+    // cancelled when the push to talk button is released or its hotkey pressed again, starts out released
+    static ref PUSH_TO_TALK_TOKEN: Mutex<CancellationToken> = Mutex::new({
+        let token = CancellationToken::new();
+        token.cancel();
+        token
+    });
+
+    // permissions granted only until Magnus is closed, by Permission name
+    static ref SESSION_PERMISSION_GRANTS: Mutex<HashMap<String, PermissionGrant>> = Mutex::new(HashMap::new());
+
     static ref AUTH_USER_ID: Mutex<String> = Mutex::new("".to_string());
 
     static ref AUTH_JWT: Mutex<String> = Mutex::new("".to_string());
@@ -130,6 +162,22 @@ pub fn set_thread_id(new_value: String) {
     *THREAD_ID.lock().unwrap() = new_value;
 }
 
+pub fn get_chat_history() -> Vec<Value> {
+    CHAT_HISTORY.lock().unwrap().clone()
+}
+
+pub fn set_chat_history(new_value: Vec<Value>) {
+    *CHAT_HISTORY.lock().unwrap() = new_value;
+}
+
+pub fn get_cancel_token() -> CancellationToken {
+    CANCEL_TOKEN.lock().unwrap().clone()
+}
+
+pub fn set_cancel_token(new_value: CancellationToken) {
+    *CANCEL_TOKEN.lock().unwrap() = new_value;
+}
+
 pub fn get_auth_domain() -> &'static String {
     &AUTH_DOMAIN
 }
@@ -146,6 +194,46 @@ pub fn set_is_signed_in(is_signed_in: bool) {
     *IS_SIGNED_IN.lock().unwrap() = is_signed_in;
 }
 
+pub fn get_is_backend_available() -> bool {
+    IS_BACKEND_AVAILABLE.lock().unwrap().clone()
+}
+
+pub fn set_is_backend_available(is_backend_available: bool) {
+    *IS_BACKEND_AVAILABLE.lock().unwrap() = is_backend_available;
+}
+
+pub fn get_last_answer() -> String {
+    LAST_ANSWER.lock().unwrap().clone()
+}
+
+pub fn set_last_answer(last_answer: String) {
+    *LAST_ANSWER.lock().unwrap() = last_answer;
+}
+
+pub fn get_is_tts_muted() -> bool {
+    IS_TTS_MUTED.lock().unwrap().clone()
+}
+
+pub fn set_is_tts_muted(is_tts_muted: bool) {
+    *IS_TTS_MUTED.lock().unwrap() = is_tts_muted;
+}
+
+pub fn get_push_to_talk_token() -> CancellationToken {
+    PUSH_TO_TALK_TOKEN.lock().unwrap().clone()
+}
+
+pub fn set_push_to_talk_token(push_to_talk_token: CancellationToken) {
+    *PUSH_TO_TALK_TOKEN.lock().unwrap() = push_to_talk_token;
+}
+
+pub fn get_session_permission_grant(permission: &str) -> Option<PermissionGrant> {
+    SESSION_PERMISSION_GRANTS.lock().unwrap().get(permission).cloned()
+}
+
+pub fn set_session_permission_grant(permission: String, grant: PermissionGrant) {
+    SESSION_PERMISSION_GRANTS.lock().unwrap().insert(permission, grant);
+}
+
 pub fn get_auth_user_id() -> String {
     AUTH_USER_ID.lock().unwrap().clone()
 }
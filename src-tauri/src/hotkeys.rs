@@ -0,0 +1,246 @@
+use crate::settings::{get_settings, SpeechInputMode};
+use crate::{cancel_conversation, globals, run_conversation_turn, speak_answer, Payload, APP_HANDLE, RUNNING_KEYBIND_FLOW};
+use clipboard::{ClipboardContext, ClipboardProvider};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use strum_macros::EnumIter;
+use tauri::{AppHandle, GlobalShortcutManager, Manager};
+use tokio_util::sync::CancellationToken;
+
+/*
+Global hotkeys are set in the "hotkeys" section of settings.json, mapping each shortcut to the action it runs, e.g.
+{ "Alt+M": "startVoiceTurn", "Alt+Shift+M": "cancel" }. Shortcuts use Tauri's accelerator format.
+
+Two shortcuts that only differ in case or modifier order are the same shortcut, so they're reported as a conflict and
+only the first is registered. Shortcuts another app already holds can't be registered either, both kinds of problem are
+kept so the frontend can show them with the bindings.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, EnumIter)]
+#[serde(rename_all = "camelCase")]
+pub enum HotkeyAction {
+    StartVoiceTurn, // listens for a question and answers it, with push to talk pressing it again ends the recording
+    Cancel, // stops whatever the current turn is doing
+    RepeatLastAnswer, // speaks the last answer again
+    ToggleMuteTts, // stops or starts answers being spoken
+    PasteClipboardAsQuestion, // asks whatever text is on the clipboard
+}
+
+lazy_static! {
+    // what went wrong the last time the hotkeys were registered
+    static ref HOTKEY_PROBLEMS: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+pub fn get_default_hotkeys() -> BTreeMap<String, HotkeyAction> {
+    BTreeMap::from([
+        ("Alt+M".to_string(), HotkeyAction::StartVoiceTurn),
+        ("Alt+Shift+M".to_string(), HotkeyAction::Cancel),
+    ])
+}
+
+pub fn get_hotkey_problems() -> Vec<String> {
+    HOTKEY_PROBLEMS.lock().unwrap().clone()
+}
+
+// puts a shortcut's modifiers in a fixed order and capitalizes its parts, so equal shortcuts are written the same way.
+// modifiers that mean the same key on this platform are written the same too, CommandOrControl is Super on macOS and
+// Ctrl everywhere else
+pub fn normalize_shortcut(shortcut: &str) -> Result<String, String> {
+    let mut modifiers: Vec<&str> = vec![];
+    let mut key: Option<String> = None;
+
+    for part in shortcut.split('+').map(str::trim) {
+        let modifier = match part.to_lowercase().as_str() {
+            "commandorcontrol" | "cmdorctrl" | "commandorctrl" | "cmdorcontrol" => {
+                if cfg!(target_os = "macos") {
+                    "Super"
+                } else {
+                    "Ctrl"
+                }
+            }
+            "ctrl" | "control" => "Ctrl",
+            "alt" | "option" => "Alt",
+            "shift" => "Shift",
+            "super" | "cmd" | "command" | "meta" => "Super",
+            "" => return Err("has an empty key".to_string()),
+            _ => {
+                if key.is_some() {
+                    return Err("has more than one key that isn't a modifier".to_string());
+                }
+                let mut chars = part.chars();
+                key = chars.next().map(|first| first.to_uppercase().chain(chars).collect());
+                continue;
+            }
+        };
+        if !modifiers.contains(&modifier) {
+            modifiers.push(modifier);
+        }
+    }
+
+    let key = key.ok_or_else(|| "has no key besides its modifiers".to_string())?;
+    // a global shortcut without a modifier would take the key away from every other app
+    if modifiers.is_empty() {
+        return Err("needs at least one modifier like Alt or Ctrl".to_string());
+    }
+
+    let order = ["Ctrl", "Alt", "Shift", "Super"];
+    modifiers.sort_by_key(|modifier| order.iter().position(|o| o == modifier));
+
+    Ok(format!("{}+{}", modifiers.join("+"), key))
+}
+
+// checks the bindings without registering anything, returning them by their normalized shortcuts along with what's
+// wrong with the ones that were left out
+pub fn check_hotkeys(
+    hotkeys: &BTreeMap<String, HotkeyAction>,
+) -> (BTreeMap<String, HotkeyAction>, Vec<String>) {
+    let mut checked: BTreeMap<String, HotkeyAction> = BTreeMap::new();
+    let mut original_shortcuts: BTreeMap<String, &str> = BTreeMap::new();
+    let mut problems: Vec<String> = vec![];
+
+    for (shortcut, action) in hotkeys {
+        let normalized = match normalize_shortcut(shortcut) {
+            Ok(normalized) => normalized,
+            Err(reason) => {
+                problems.push(format!("\"{}\" {}", shortcut, reason));
+                continue;
+            }
+        };
+
+        match original_shortcuts.get(&normalized) {
+            Some(other) => problems.push(format!(
+                "\"{}\" and \"{}\" are the same shortcut, only \"{}\" is used",
+                other, shortcut, other
+            )),
+            None => {
+                original_shortcuts.insert(normalized.clone(), shortcut);
+                checked.insert(normalized, *action);
+            }
+        }
+    }
+
+    (checked, problems)
+}
+
+// registers every binding in settings.json in place of the ones registered before, returning what went wrong
+pub fn register_hotkeys(app_handle: &AppHandle) -> Vec<String> {
+    let (hotkeys, mut problems) = check_hotkeys(&get_settings().hotkeys);
+    let mut shortcuts = app_handle.global_shortcut_manager();
+
+    if let Err(err) = shortcuts.unregister_all() {
+        problems.push(format!("the previous hotkeys couldn't be removed ({})", err));
+    }
+
+    for (shortcut, action) in hotkeys {
+        match shortcuts.register(&shortcut, move || run_hotkey_action(action)) {
+            Ok(_) => println!("Registered {} for {:?}", shortcut, action),
+            Err(err) => problems.push(format!(
+                "\"{}\" couldn't be registered, another app may be using it ({})",
+                shortcut, err
+            )),
+        }
+    }
+
+    for problem in &problems {
+        println!("Hotkey problem: {}", problem);
+    }
+    *HOTKEY_PROBLEMS.lock().unwrap() = problems.clone();
+
+    problems
+}
+
+fn run_hotkey_action(action: HotkeyAction) {
+    let app_handle = APP_HANDLE.lock().unwrap().clone();
+    let app_handle = match app_handle {
+        Some(app_handle) => app_handle,
+        None => return,
+    };
+
+    match action {
+        HotkeyAction::StartVoiceTurn => match get_settings().speech_input_mode {
+            // global shortcuts only report key presses, not releases, so for hotkeys push to talk is a toggle:
+            // one press starts the recording and the next one ends it
+            SpeechInputMode::PushToTalk => {
+                let push_to_talk_token = globals::get_push_to_talk_token();
+                if push_to_talk_token.is_cancelled() {
+                    let push_to_talk_token = CancellationToken::new();
+                    globals::set_push_to_talk_token(push_to_talk_token.clone());
+                    if !start_keybind_flow(app_handle, None) {
+                        push_to_talk_token.cancel();
+                    }
+                } else {
+                    push_to_talk_token.cancel();
+                }
+            }
+            SpeechInputMode::Silence => {
+                start_keybind_flow(app_handle, None);
+            }
+        },
+        HotkeyAction::Cancel => cancel_conversation(),
+        HotkeyAction::RepeatLastAnswer => {
+            let last_answer = globals::get_last_answer();
+            if last_answer.trim().is_empty() {
+                println!("No answer to repeat");
+                return;
+            }
+
+            // a turn that's still running keeps its token, replacing it would leave that turn impossible to cancel.
+            // sharing it means cancelling stops both the turn and the repeat
+            let mut cancel_token = globals::get_cancel_token();
+            if cancel_token.is_cancelled() {
+                cancel_token = CancellationToken::new();
+                globals::set_cancel_token(cancel_token.clone());
+            }
+            speak_answer(app_handle, &last_answer, cancel_token);
+        }
+        HotkeyAction::ToggleMuteTts => {
+            let is_muted = !globals::get_is_tts_muted();
+            globals::set_is_tts_muted(is_muted);
+
+            let status = if is_muted { "muted" } else { "unmuted" };
+            println!("Speech {}", status);
+            let _ = app_handle.emit_all(
+                "tts-muted",
+                Payload {
+                    message: status.to_string(),
+                },
+            );
+        }
+        HotkeyAction::PasteClipboardAsQuestion => {
+            let text = ClipboardContext::new().and_then(|mut clipboard| clipboard.get_contents());
+            match text {
+                Ok(text) if !text.trim().is_empty() => {
+                    start_keybind_flow(app_handle, Some(text));
+                }
+                Ok(_) => println!("Nothing on the clipboard to ask"),
+                Err(err) => println!("Error getting clipboard contents: {}", err),
+            }
+        }
+    }
+}
+
+// runs a conversation turn from a hotkey, only one at a time. returns false if one was already running
+pub fn start_keybind_flow(app_handle: AppHandle, user_message: Option<String>) -> bool {
+    let mut running_keybind_flow = RUNNING_KEYBIND_FLOW.lock().unwrap();
+    if *running_keybind_flow {
+        println!("Already running keybind flow!");
+        return false;
+    }
+    *running_keybind_flow = true;
+
+    let cancel_token = CancellationToken::new();
+    globals::set_cancel_token(cancel_token.clone());
+
+    // begin flow
+    tauri::async_runtime::spawn(async move {
+        run_conversation_turn(app_handle, user_message, cancel_token.clone()).await;
+
+        // a cancelled flow was already released, and another may have started since
+        if !cancel_token.is_cancelled() {
+            *RUNNING_KEYBIND_FLOW.lock().unwrap() = false;
+        }
+    });
+
+    true
+}
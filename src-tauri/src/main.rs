@@ -4,24 +4,38 @@
 use cpal::traits::DeviceTrait;
 use db::{Log, User};
 use dotenv;
+use error::MagnusError;
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
+use strum::IntoEnumIterator;
 use std::thread;
-use tauri::{AppHandle, GlobalShortcutManager, Manager};
+use tauri::{AppHandle, Manager};
 use tokio::runtime::Runtime;
+use tokio_util::sync::CancellationToken;
 
 mod assistant;
+mod audio_config;
 mod audio_input;
 mod audio_output;
+mod chat_backend;
+mod confirmation;
 mod db;
+mod error;
 mod globals;
+mod hotkeys;
+mod request;
 mod settings;
 mod tools;
+mod wake_word;
 
 lazy_static! {
     static ref APP_HANDLE: Arc<Mutex<Option<AppHandle>>> = Arc::new(Mutex::new(None));
+
+    // limits the keybind flow to one at a time
+    static ref RUNNING_KEYBIND_FLOW: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
 }
 
 #[derive(Clone, serde::Serialize)]
@@ -29,17 +43,15 @@ pub struct Payload {
     message: String,
 }
 
-async fn create_message_thread() -> String {
-    let result = assistant::create_message_thread().await;
-
-    match result {
-        Ok(thread_id) => {
-            globals::set_thread_id(thread_id.clone().trim_matches('\"').to_string());
-            println!("Successfully created thread: {}", globals::get_thread_id());
-            thread_id
-        }
-        Err(_) => panic!("Error creating the message thread!"),
-    }
+// tells the frontend something went wrong during a conversation turn
+fn emit_error(app_handle: &AppHandle, err: MagnusError) {
+    println!("Error: {:?}", err);
+    let _ = app_handle.emit_all(
+        "error",
+        Payload {
+            message: err.to_string(),
+        },
+    );
 }
 
 #[tauri::command]
@@ -77,6 +89,11 @@ fn set_is_signed_in(is_signed_in: bool) {
     globals::set_is_signed_in(is_signed_in)
 }
 
+#[tauri::command]
+fn get_is_backend_available() -> bool {
+    globals::get_is_backend_available()
+}
+
 #[tauri::command]
 fn get_auth_client_id() -> String {
     globals::get_auth_client_id().to_string()
@@ -87,14 +104,39 @@ fn get_auth_domain() -> String {
     globals::get_auth_domain().to_string()
 }
 
+// the frontend only deals with the grants, by Permission name
 #[tauri::command]
 fn get_permissions() -> Value {
-    settings::get_permissions()
+    serde_json::to_value(settings::get_permissions().grants).unwrap_or_default()
 }
 
 #[tauri::command]
 fn update_permissions(permissions: Value) {
-    settings::update_permissions(permissions)
+    if let Some(grants) = permissions.as_object() {
+        settings::update_permission_grants(grants)
+    }
+}
+
+// changes a permission from the frontend, e.g. allowing screenshots for the next hour or until Magnus is closed
+#[tauri::command]
+fn grant_permission(
+    permission: String,
+    mode: settings::PermissionMode,
+    expires_in_seconds: Option<u64>,
+    session_only: bool,
+) -> bool {
+    settings::grant_permission(&permission, mode, expires_in_seconds, session_only)
+}
+
+// the user's answer to a "confirm-action" event, false if that confirmation is no longer waiting
+#[tauri::command]
+fn approve_action(id: u64) -> bool {
+    confirmation::resolve_confirmation(id, true)
+}
+
+#[tauri::command]
+fn deny_action(id: u64) -> bool {
+    confirmation::resolve_confirmation(id, false)
 }
 
 #[tauri::command]
@@ -137,32 +179,191 @@ fn get_audio_output_devices() -> Value {
 
 #[tauri::command]
 fn audio_input_device_selection(device_name: String) {
-    let mut settings = settings::get_settings().as_object_mut().unwrap().clone();
-    settings.insert(
-        "audioInputDeviceSelection".to_string(),
-        Into::<Value>::into(device_name),
-    );
-    settings::update_settings(Into::<Value>::into(settings));
+    settings::modify_settings(|settings| settings.audio_input_device_selection = Some(device_name));
 }
 
 #[tauri::command]
 fn audio_output_device_selection(device_name: String) {
-    let mut settings = settings::get_settings().as_object_mut().unwrap().clone();
-    settings.insert(
-        "audioOutputDeviceSelection".to_string(),
-        Into::<Value>::into(device_name),
-    );
-    settings::update_settings(Into::<Value>::into(settings));
+    settings::modify_settings(|settings| settings.audio_output_device_selection = Some(device_name));
+}
+
+// the preferred stream config for a device along with the configs it supports
+#[tauri::command]
+fn get_audio_device_config(
+    device_name: String,
+    direction: audio_config::AudioDirection,
+) -> Result<Value, String> {
+    let device = audio_config::find_device(&device_name, direction)
+        .ok_or_else(|| format!("{} isn't connected", device_name))?;
+
+    let mut device_config = audio_config::get_supported_configs_json(&device, direction)?;
+    device_config["preferred"] =
+        serde_json::to_value(audio_config::get_preferred_config(&device, direction).unwrap_or_default())
+            .unwrap_or_default();
+    Ok(device_config)
+}
+
+// saves a preferred stream config for a device once it's known the device supports it, an empty config goes back to
+// the device's default
+#[tauri::command]
+fn set_audio_device_config(
+    device_name: String,
+    direction: audio_config::AudioDirection,
+    config: audio_config::AudioDeviceConfig,
+) -> Result<(), String> {
+    if !config.is_empty() {
+        let device = audio_config::find_device(&device_name, direction)
+            .ok_or_else(|| format!("{} isn't connected", device_name))?;
+        audio_config::check_device_config(&device, direction, &config)
+            .map_err(|reason| format!("{} can't use that config, {}", device_name, reason))?;
+    }
+
+    settings::modify_settings(|settings| {
+        let device_configs = match direction {
+            audio_config::AudioDirection::Input => &mut settings.audio_input_device_configs,
+            audio_config::AudioDirection::Output => &mut settings.audio_output_device_configs,
+        };
+        if config.is_empty() {
+            device_configs.remove(&device_name);
+        } else {
+            device_configs.insert(device_name, config);
+        }
+    });
+    Ok(())
+}
+
+// the hotkey bindings, every action they can run and anything that stopped a binding from being registered
+#[tauri::command]
+fn get_hotkeys() -> Value {
+    json!({
+        "bindings": settings::get_settings().hotkeys,
+        "actions": hotkeys::HotkeyAction::iter().collect::<Vec<_>>(),
+        "problems": hotkeys::get_hotkey_problems()
+    })
+}
+
+// replaces every binding, bindings that conflict with each other aren't saved. saving re-registers the hotkeys, which
+// can't happen on the main thread, so this is async
+#[tauri::command]
+async fn update_hotkeys(
+    bindings: BTreeMap<String, hotkeys::HotkeyAction>,
+) -> Result<Vec<String>, String> {
+    let (bindings, problems) = hotkeys::check_hotkeys(&bindings);
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+
+    settings::modify_settings(|settings| settings.hotkeys = bindings);
+    Ok(hotkeys::get_hotkey_problems())
+}
+
+#[tauri::command]
+fn cancel_conversation() {
+    globals::get_cancel_token().cancel();
+
+    // release the keybind flow right away so a new one can be started
+    *RUNNING_KEYBIND_FLOW.lock().unwrap() = false;
+    println!("Cancelled conversation!");
+}
+
+#[tauri::command]
+fn get_speech_input_mode() -> settings::SpeechInputMode {
+    settings::get_settings().speech_input_mode
+}
+
+#[tauri::command]
+fn set_speech_input_mode(mode: settings::SpeechInputMode) {
+    settings::modify_settings(|settings| settings.speech_input_mode = mode);
+}
+
+// starts a push to talk turn when the mic button is pressed, recording until stop_push_to_talk. like stop_push_to_talk
+// this runs on the main thread, so a quick click's stop can't be handled before its start
+#[tauri::command]
+fn start_push_to_talk(app_handle: AppHandle) {
+    globals::set_push_to_talk_token(CancellationToken::new());
+    tauri::async_runtime::spawn(run_conversation_flow(app_handle, None));
+}
+
+#[tauri::command]
+fn stop_push_to_talk() {
+    globals::get_push_to_talk_token().cancel();
+}
+
+// whether Magnus listens for the wake phrase, the phrase and what the listener is doing right now
+#[tauri::command]
+fn get_wake_word() -> Value {
+    let settings = settings::get_settings();
+    json!({
+        "enabled": settings.wake_word_enabled,
+        "phrase": settings.wake_phrase,
+        "state": wake_word::get_listening_state().as_str()
+    })
+}
+
+#[tauri::command]
+fn set_wake_word(enabled: bool, phrase: String) -> Result<(), String> {
+    if enabled && phrase.trim().is_empty() {
+        return Err("The wake phrase can't be empty".to_string());
+    }
+    settings::modify_settings(|settings| {
+        settings.wake_word_enabled = enabled;
+        settings.wake_phrase = phrase;
+    });
+    Ok(())
+}
+
+// transcribes a WAV or Ogg/Opus file, and with ask asks Magnus what was said in it
+#[tauri::command]
+async fn transcribe_file(app_handle: AppHandle, path: String, ask: bool) -> Result<Option<String>, String> {
+    let transcription = tokio::task::spawn_blocking(move || audio_input::transcribe_file(&path))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())?;
+
+    if let (true, Some(question)) = (ask, transcription.clone()) {
+        tauri::async_runtime::spawn(run_conversation_flow(app_handle, Some(question)));
+    }
+    Ok(transcription)
 }
 
 #[tauri::command]
 async fn run_conversation_flow(app_handle: AppHandle, user_message: Option<String>) {
+    let cancel_token = CancellationToken::new();
+    globals::set_cancel_token(cancel_token.clone());
+
+    run_conversation_turn(app_handle, user_message, cancel_token).await;
+}
+
+async fn run_conversation_turn(
+    app_handle: AppHandle,
+    user_message: Option<String>,
+    cancel_token: CancellationToken,
+) {
+    // the wake word listener would hear the user and the answer too
+    let _wake_word_pause = wake_word::pause_wake_word();
+
     // if we have no user message, attempt to get speech input
     let user_message = match user_message {
         Some(message) => Some(message),
-        None => audio_input::run(),
+        None => match audio_input::run(cancel_token.clone()) {
+            Ok(transcription) => transcription,
+            Err(err) => {
+                emit_error(&app_handle, err);
+                return;
+            }
+        },
     };
 
+    if cancel_token.is_cancelled() {
+        let _ = app_handle.emit_all(
+            "cancelled",
+            Payload {
+                message: "Cancelled".to_string(),
+            },
+        );
+        return;
+    }
+
     // if there is a user message from either text or speech input, run the flow
     match user_message {
         Some(user_message) => {
@@ -174,7 +375,23 @@ async fn run_conversation_flow(app_handle: AppHandle, user_message: Option<Strin
                 },
             );
 
-            let assistant_message = assistant::run(user_message).await;
+            let assistant_message = match assistant::run(user_message, cancel_token.clone()).await {
+                Ok(Some(assistant_message)) => assistant_message,
+                Err(err) => {
+                    emit_error(&app_handle, err);
+                    return;
+                }
+                Ok(None) => {
+                    println!("Magnus: (cancelled)");
+                    let _ = app_handle.emit_all(
+                        "cancelled",
+                        Payload {
+                            message: "Cancelled".to_string(),
+                        },
+                    );
+                    return;
+                }
+            };
             println!("Magnus: {assistant_message}");
             let _ = app_handle.emit_all(
                 "magnus",
@@ -182,27 +399,9 @@ async fn run_conversation_flow(app_handle: AppHandle, user_message: Option<Strin
                     message: assistant_message.clone(),
                 },
             );
+            globals::set_last_answer(assistant_message.clone());
 
-            // exclude code snippets from tts
-            let code_snippets_regex = Regex::new(r"`{3}[\s\S]+?`{3}").unwrap();
-            let text_to_speak = code_snippets_regex
-                .split(&assistant_message)
-                .collect::<Vec<_>>()
-                .join("\n");
-            let should_tts: bool = settings::get_permissions()
-                .get("Tts")
-                .unwrap()
-                .as_bool()
-                .unwrap();
-
-            if should_tts && text_to_speak.trim() != "" {
-                thread::spawn(move || {
-                    let rt = Runtime::new().unwrap();
-                    rt.block_on(async {
-                        let _ = audio_output::speak(text_to_speak.clone()).await;
-                    });
-                });
-            }
+            speak_answer(app_handle, &assistant_message, cancel_token);
         }
         None => {
             println!("No message from user");
@@ -210,7 +409,61 @@ async fn run_conversation_flow(app_handle: AppHandle, user_message: Option<Strin
     }
 }
 
+// speaks an answer in the background, unless speech is muted or not allowed
+fn speak_answer(app_handle: AppHandle, answer: &str, cancel_token: CancellationToken) {
+    // exclude code snippets from tts
+    let code_snippets_regex = Regex::new(r"`{3}[\s\S]+?`{3}").unwrap();
+    let text_to_speak = code_snippets_regex
+        .split(answer)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let should_tts: bool = !globals::get_is_tts_muted()
+        && settings::get_permission_mode(&settings::Permission::Tts) == settings::PermissionMode::Allow;
+
+    if should_tts && text_to_speak.trim() != "" {
+        let wake_word_pause = wake_word::pause_wake_word();
+        thread::spawn(move || {
+            let _wake_word_pause = wake_word_pause;
+            let rt = Runtime::new().unwrap();
+            rt.block_on(async {
+                if let Err(err) = audio_output::speak(text_to_speak.clone(), cancel_token).await {
+                    emit_error(&app_handle, err);
+                }
+            });
+        });
+    }
+}
+
+// `magnus --transcribe <path>` prints what's said in a WAV or Ogg/Opus file instead of opening the app, "-" reads the
+// file from stdin. returns the exit code
+fn run_transcribe_command(path: Option<&str>) -> i32 {
+    let transcription = match path {
+        Some("-") => audio_input::transcribe_stdin(),
+        Some(path) => audio_input::transcribe_file(path),
+        None => {
+            eprintln!("Usage: magnus --transcribe <path to a WAV or Ogg/Opus file, or - for stdin>");
+            return 2;
+        }
+    };
+
+    match transcription {
+        Ok(transcription) => {
+            println!("{}", transcription.unwrap_or_default());
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--transcribe") {
+        std::process::exit(run_transcribe_command(args.get(index + 1).map(String::as_str)));
+    }
+
     // load env
     if cfg!(debug_assertions) {
         dotenv::dotenv().ok();
@@ -241,56 +494,49 @@ fn main() {
     }
 
     // setups before app build
-    let running_keybind_flow = Arc::new(Mutex::new(false));
-
     let _ = globals::get_vosk_model();
 
-    tauri::async_runtime::block_on(async {
-        create_message_thread().await;
-    });
-
     tauri::Builder::default()
         .setup(move |app| {
             let app_handle = app.handle();
             *APP_HANDLE.lock().unwrap() = Some(app_handle.clone());
-            let mut shortcuts = app_handle.global_shortcut_manager();
-            let running_keybind_flow_clone = running_keybind_flow.clone();
-
-            // keybind to begin mic input and assistant response flow, might make this adjustable later
-            let _ = shortcuts.register("Alt+M", move || {
-                let mut running_keybind_flow = running_keybind_flow_clone.lock().unwrap();
-
-                // limits this to one flow/thread at a time
-                if !*running_keybind_flow {
-                    *running_keybind_flow = true;
-                    let running_keybind_flow_clone = running_keybind_flow_clone.clone();
-
-                    let app_handle_clone = app_handle.clone();
-                    // begin flow
-
-                    tauri::async_runtime::spawn(async move {
-                        run_conversation_flow(app_handle_clone, None).await;
-                        let mut running_keybind_flow = running_keybind_flow_clone.lock().unwrap();
-                        *running_keybind_flow = false;
-                    });
-                } else {
-                    println!("Already running keybind flow!");
-                }
-            });
+
+            // pick up hand edits to settings.json and permissions.json while Magnus is running
+            settings::watch_settings_files();
+            // the keybinds for starting a turn, cancelling it and so on are set in settings.json
+            hotkeys::register_hotkeys(&app_handle);
+            // listens for "hey magnus" if it's turned on
+            wake_word::update_wake_word_listener();
 
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             run_conversation_flow,
+            cancel_conversation,
+            get_hotkeys,
+            get_speech_input_mode,
+            set_speech_input_mode,
+            start_push_to_talk,
+            stop_push_to_talk,
+            get_wake_word,
+            transcribe_file,
+            set_wake_word,
+            update_hotkeys,
             get_permissions,
             update_permissions,
+            grant_permission,
+            approve_action,
+            deny_action,
             get_audio_input_devices,
             get_audio_output_devices,
             audio_input_device_selection,
             audio_output_device_selection,
+            get_audio_device_config,
+            set_audio_device_config,
             get_auth_client_id,
             get_auth_domain,
             set_is_signed_in,
+            get_is_backend_available,
             create_user,
             create_log,
             set_user_id,
@@ -0,0 +1,162 @@
+use crate::error::MagnusError;
+use crate::settings::get_settings;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/*
+Every HTTP request Magnus makes goes through here, so they all share the same timeouts and retry behaviour.
+
+Requests that time out, can't connect, are rate limited (429) or hit a server error (5xx) are retried with exponential
+backoff plus some random jitter, unless the server told us how long to wait with a Retry-After header. Other responses
+are returned as they are, it's up to the caller to decide what an unsuccessful status means (see check_response).
+
+Requests that aren't idempotent, like a POST adding a message to a thread, may already have been handled by a server
+that timed out or failed afterwards, and sending them again would do it twice. Those are only retried when they can't
+have reached the server: it couldn't be connected to, it rate limited us, or a gateway in front of it (502, 503, 504)
+answered instead.
+
+Streamed responses can't have a timeout for the whole request since their body arrives over time, but they still get
+requestTimeoutSeconds to start responding, and then the same again between each chunk of the body (see
+get_stream_idle_timeout). Otherwise a server that accepted the connection and stalled would leave the turn waiting
+forever.
+*/
+const BASE_RETRY_DELAY_MILLIS: u64 = 500;
+const MAX_RETRY_DELAY_MILLIS: u64 = 20_000;
+
+// how long a whole request may take, configurable through "requestTimeoutSeconds" in settings.json
+fn get_request_timeout() -> Duration {
+    Duration::from_secs(get_settings().request_timeout_seconds)
+}
+
+// how many times a request is retried, configurable through "requestMaxRetries" in settings.json
+fn get_max_retries() -> u64 {
+    get_settings().request_max_retries
+}
+
+// how long a streamed response may go without sending anything before it's given up on
+pub fn get_stream_idle_timeout() -> Duration {
+    get_request_timeout()
+}
+
+pub fn get_stream_timeout_error(timeout: Duration) -> MagnusError {
+    MagnusError::Network(format!("the server stopped responding for {}s", timeout.as_secs()))
+}
+
+// sends a request whose response is read all at once
+pub async fn send_with_retry(request: RequestBuilder) -> Result<Response, MagnusError> {
+    send(request, false).await
+}
+
+// sends a request whose response is streamed, the timeout only covers waiting for the response to start
+pub async fn send_stream_with_retry(request: RequestBuilder) -> Result<Response, MagnusError> {
+    send(request, true).await
+}
+
+// what came of sending a request once
+enum Attempt {
+    Response(Response),
+    Failed(reqwest::Error),
+    StreamTimedOut, // a streamed response didn't start in time
+}
+
+async fn send_once(request: RequestBuilder, timeout: Duration, streamed: bool) -> Attempt {
+    let result = if streamed {
+        match tokio::time::timeout(timeout, request.send()).await {
+            Ok(result) => result,
+            Err(_) => return Attempt::StreamTimedOut,
+        }
+    } else {
+        request.timeout(timeout).send().await
+    };
+
+    match result {
+        Ok(response) => Attempt::Response(response),
+        Err(err) => Attempt::Failed(err),
+    }
+}
+
+async fn send(request: RequestBuilder, streamed: bool) -> Result<Response, MagnusError> {
+    let timeout = get_request_timeout();
+    let max_retries = get_max_retries();
+    let mut attempt: u64 = 0;
+    let is_idempotent = request
+        .try_clone()
+        .and_then(|request| request.build().ok())
+        .map_or(false, |request| request.method().is_idempotent());
+
+    loop {
+        // requests with a streamed body can't be cloned, so they only get the one attempt
+        let this_attempt = match request.try_clone() {
+            Some(this_attempt) if attempt < max_retries => this_attempt,
+            _ => {
+                return match send_once(request, timeout, streamed).await {
+                    Attempt::Response(response) => Ok(response),
+                    Attempt::Failed(err) => Err(err.into()),
+                    Attempt::StreamTimedOut => Err(get_stream_timeout_error(timeout)),
+                }
+            }
+        };
+
+        let retry_after = match send_once(this_attempt, timeout, streamed).await {
+            Attempt::Response(response) if should_retry(response.status(), is_idempotent) => {
+                println!("Request returned {}, retrying.", response.status());
+                get_retry_after(response.headers())
+            }
+            Attempt::Response(response) => return Ok(response),
+            Attempt::Failed(err) if err.is_connect() || (err.is_timeout() && is_idempotent) => {
+                println!("Request failed: {}, retrying.", err);
+                None
+            }
+            Attempt::Failed(err) => return Err(err.into()),
+            Attempt::StreamTimedOut if is_idempotent => {
+                println!("Request didn't respond within {}s, retrying.", timeout.as_secs());
+                None
+            }
+            Attempt::StreamTimedOut => return Err(get_stream_timeout_error(timeout)),
+        };
+
+        tokio::time::sleep(retry_after.unwrap_or_else(|| get_backoff(attempt))).await;
+        attempt += 1;
+    }
+}
+
+fn should_retry(status: StatusCode, is_idempotent: bool) -> bool {
+    match status {
+        StatusCode::TOO_MANY_REQUESTS
+        | StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT => true,
+        status => is_idempotent && status.is_server_error(),
+    }
+}
+
+// reads how long the server wants us to wait, from either Retry-After (seconds) or OpenAI's retry-after-ms
+fn get_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<f64>().ok())
+    };
+
+    let millis = match header("retry-after-ms") {
+        Some(millis) => millis,
+        None => header("retry-after")? * 1000.0,
+    };
+
+    Some(Duration::from_millis(millis.clamp(0.0, MAX_RETRY_DELAY_MILLIS as f64) as u64))
+}
+
+// doubles the delay every attempt, with up to half of it added as jitter so retries don't all land at once
+fn get_backoff(attempt: u64) -> Duration {
+    let delay = (BASE_RETRY_DELAY_MILLIS << attempt.min(10)).min(MAX_RETRY_DELAY_MILLIS);
+
+    // the sub-second part of the clock is random enough for spreading out retries
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.subsec_nanos() as u64)
+        .unwrap_or_default();
+    let jitter = nanos % (delay / 2 + 1);
+
+    Duration::from_millis(delay + jitter)
+}
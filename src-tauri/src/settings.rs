@@ -1,16 +1,53 @@
-use cpal::traits::DeviceTrait;
 use Permission::*;
+use lazy_static::lazy_static;
+use notify::{RecursiveMode, Watcher};
 use serde_json::{to_string_pretty, Map, Value};
-use std::{ fs::{self, File}, io::Read, path::PathBuf};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashMap}, fs, io::Write, path::PathBuf, sync::{mpsc, Mutex}, thread, time::{Duration, SystemTime, UNIX_EPOCH}};
 use strum_macros::EnumIter;
 use strum::IntoEnumIterator;
+use tauri::Manager;
 
-use crate::{audio_input, audio_output};
+use crate::audio_config::AudioDeviceConfig;
+use crate::error::MagnusError;
+use crate::globals;
+use crate::hotkeys::{self, HotkeyAction};
+use crate::wake_word;
+use crate::APP_HANDLE;
+
+const DEFAULT_API_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_CHAT_MODEL: &str = "gpt-4o";
+const DEFAULT_SPEECH_MODEL: &str = "tts-1";
+
+/*
+settings.json and permissions.json are read into the typed Settings and Permissions structs below. Every field has a
+default, so a file from an older version that is missing keys still loads, and the defaults are written back so the
+file shows everything that can be changed.
+
+Each file has a "version". When the layout of a file changes, bump its version and add a step to its migrate function
+that turns the previous version into the new one. Files written before versions existed are version 0.
+
+A file that can't be read or parsed is moved aside to <name>.corrupt-<unix time>.json, so nothing the user wrote is
+lost, and a fresh one is created in its place.
+
+Both files are read once and then kept in memory. Changes go through modify_settings and modify_permissions, which hold
+the lock for the whole read-modify-write so two commands from the UI can't overwrite each other's changes. Files are
+written to a temporary file first and then renamed over the old one, so a crash mid-write never leaves a truncated
+file behind. Every change is sent to the frontend as a "settings-changed" or "permissions-changed" event.
+*/
+const SETTINGS_VERSION: u64 = 1;
+const PERMISSIONS_VERSION: u64 = 1;
+
+lazy_static! {
+    // None until the file is first read
+    static ref SETTINGS: Mutex<Option<Settings>> = Mutex::new(None);
+    static ref PERMISSIONS: Mutex<Option<Permissions>> = Mutex::new(None);
+}
 
 pub fn get_magnus_data_dir_path() -> PathBuf {
     let mut path = tauri::api::path::data_dir().unwrap();
     path.push("magnus");
-    path    
+    path
 }
 
 #[derive(Clone, EnumIter)]
@@ -34,89 +71,420 @@ impl Permission {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionMode {
+    Allow,
+    Deny,
+    Ask // the user confirms every use of the permission
+}
+
+/*
+What permissions.json holds for each Permission, e.g. { "mode": "allow", "expiresAt": 1718000000 }.
+
+A grant with expiresAt (unix seconds) only lasts until then, after which Magnus goes back to asking. Grants can also be
+made for just this session, those are kept in globals.rs instead of the file and win over it until Magnus is closed.
+*/
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PermissionGrant {
+    pub mode: PermissionMode,
+    #[serde(rename = "expiresAt", default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>
+}
+
+impl PermissionGrant {
+    pub fn new(mode: PermissionMode, expires_in_seconds: Option<u64>) -> Self {
+        PermissionGrant {
+            mode,
+            expires_at: expires_in_seconds.map(|seconds| get_unix_time() + seconds)
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= get_unix_time(),
+            None => false
+        }
+    }
+
+    pub fn get_mode(&self) -> PermissionMode {
+        if self.is_expired() { PermissionMode::Ask } else { self.mode }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    pub version: u64,
+    pub grants: BTreeMap<String, PermissionGrant> // by Permission name, kept in order so the frontend can compare them
+}
+
+impl Default for Permissions {
+    // everything is denied until the user allows it
+    fn default() -> Self {
+        let mut grants = BTreeMap::new();
+        for permission in Permission::iter() {
+            grants.insert(permission.as_str().to_string(), PermissionGrant::new(PermissionMode::Deny, None));
+        }
+
+        Permissions { version: PERMISSIONS_VERSION, grants }
+    }
+}
+
+// how Magnus knows the user has finished asking their question
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SpeechInputMode {
+    Silence, // stops once the user goes quiet
+    PushToTalk // records while the mic button is held, or from one press of the hotkey to the next
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Settings {
+    pub version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_input_device_selection: Option<String>, // the system default is used until a device is picked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_output_device_selection: Option<String>,
+    pub audio_input_device_configs: BTreeMap<String, AudioDeviceConfig>, // preferred stream configs by device name
+    pub audio_output_device_configs: BTreeMap<String, AudioDeviceConfig>,
+    pub chat_backend: String, // "assistants" or "chatCompletions"
+    pub api_base_url: String,
+    pub chat_model: String,
+    pub speech_model: String,
+    pub request_timeout_seconds: u64, // how long a whole request may take
+    pub request_max_retries: u64,
+    pub tool_timeout_seconds: HashMap<String, u64>, // by tool name, or "default" for every other tool
+    pub max_tool_rounds: u64, // how many times the model may ask for tools in one turn
+    pub confirmation_timeout_seconds: u64, // how long to wait for the user to confirm a tool
+    pub hotkeys: BTreeMap<String, HotkeyAction>, // global shortcuts and the actions they run
+    pub speech_input_mode: SpeechInputMode,
+    pub no_speech_timeout_ms: u64, // how long to wait for the user to start talking
+    pub no_audio_timeout_ms: u64, // how long the input device can go without sending audio
+    pub end_of_utterance_silence_ms: u64, // how long the user has to be quiet after talking to end their question
+    pub filler_transcripts: Vec<String>, // transcripts that are ignored, e.g. a cough recognized as "huh"
+    pub wake_word_enabled: bool, // listen all the time for the wake phrase
+    pub wake_phrase: String,
+    #[serde(flatten)]
+    pub other: Map<String, Value> // keys this version doesn't know about, kept so saving doesn't lose them
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: SETTINGS_VERSION,
+            audio_input_device_selection: None,
+            audio_output_device_selection: None,
+            audio_input_device_configs: BTreeMap::new(),
+            audio_output_device_configs: BTreeMap::new(),
+            chat_backend: "assistants".to_string(),
+            api_base_url: DEFAULT_API_BASE_URL.to_string(),
+            chat_model: DEFAULT_CHAT_MODEL.to_string(),
+            speech_model: DEFAULT_SPEECH_MODEL.to_string(),
+            request_timeout_seconds: 30,
+            request_max_retries: 3,
+            tool_timeout_seconds: HashMap::new(),
+            max_tool_rounds: 5,
+            confirmation_timeout_seconds: 60,
+            hotkeys: hotkeys::get_default_hotkeys(),
+            speech_input_mode: SpeechInputMode::Silence,
+            no_speech_timeout_ms: 3000,
+            no_audio_timeout_ms: 3000,
+            end_of_utterance_silence_ms: 1500,
+            filler_transcripts: vec!["huh".to_string()],
+            wake_word_enabled: false,
+            wake_phrase: "hey magnus".to_string(),
+            other: Map::new()
+        }
+    }
+}
+
+fn get_unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default()
+}
+
+fn get_version(json: &Map<String, Value>) -> u64 {
+    json.get("version").and_then(Value::as_u64).unwrap_or(0)
+}
+
+// reads a grant from permissions.json, including the version 0 format of true/false or "ask"
+fn parse_permission_grant(value: &Value) -> Option<PermissionGrant> {
+    match value {
+        Value::Bool(true) => Some(PermissionGrant::new(PermissionMode::Allow, None)),
+        Value::Bool(false) => Some(PermissionGrant::new(PermissionMode::Deny, None)),
+        Value::String(_) => serde_json::from_value::<PermissionMode>(value.clone())
+            .ok()
+            .map(|mode| PermissionGrant::new(mode, None)),
+        _ => serde_json::from_value::<PermissionGrant>(value.clone()).ok()
+    }
+}
+
+fn migrate_settings(mut settings: Map<String, Value>) -> Map<String, Value> {
+    let mut version = get_version(&settings);
+
+    while version < SETTINGS_VERSION {
+        match version {
+            // version 1 only added "version" itself
+            0 => {}
+            _ => {}
+        }
+        version += 1;
+    }
+
+    settings.insert("version".to_string(), Value::from(version));
+    settings
+}
+
+fn migrate_permissions(mut permissions: Map<String, Value>) -> Map<String, Value> {
+    let mut version = get_version(&permissions);
+
+    while version < PERMISSIONS_VERSION {
+        match version {
+            // version 0 was a flat map of Permission names to true/false, and briefly to "ask" or a grant
+            0 => {
+                let grants: Map<String, Value> = permissions
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        parse_permission_grant(value).map(|grant| (name.clone(), serde_json::to_value(grant).unwrap()))
+                    })
+                    .collect();
+
+                permissions = Map::new();
+                permissions.insert("grants".to_string(), Value::Object(grants));
+            }
+            _ => {}
+        }
+        version += 1;
+    }
+
+    permissions.insert("version".to_string(), Value::from(version));
+    permissions
+}
+
+// moves a file that couldn't be loaded out of the way, keeping it for the user to look at
+fn backup_corrupt_file(path: &PathBuf) {
+    let file_stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("file");
+    let backup_path = path.with_file_name(format!("{}.corrupt-{}.json", file_stem, get_unix_time()));
+
+    match fs::rename(path, &backup_path) {
+        Ok(_) => println!("Moved unreadable {:?} to {:?}", path, backup_path),
+        Err(err) => println!("Error moving unreadable {:?}: {}", path, err),
+    }
+}
+
+// writes the whole file next to the old one and then swaps it in, the rename replaces the old file in one step
+fn write_json_file<T: Serialize>(path: &PathBuf, value: &T) {
+    // create the magnus directory within the system's app data directory
+    let _ = fs::create_dir_all(get_magnus_data_dir_path());
+
+    let pretty_json = to_string_pretty(value).unwrap();
+    let temp_path = path.with_extension("json.tmp");
+    let result = fs::File::create(&temp_path)
+        .and_then(|mut file| {
+            file.write_all(pretty_json.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp_path, path));
+
+    if let Err(err) = result {
+        println!("Error writing {:?}: {}", path, err);
+        let _ = fs::remove_file(&temp_path);
+    }
+}
+
+fn emit_change<T: Serialize + Clone>(event: &str, payload: T) {
+    if let Some(app_handle) = APP_HANDLE.lock().unwrap().as_ref() {
+        let _ = app_handle.emit_all(event, payload);
+    }
+}
+
+// reads one of the files in the magnus directory, migrating it to the current version and filling in any missing keys
+// with their defaults. None if the file doesn't exist or is empty, otherwise the value and whether the file needs saving
+// because something was migrated or filled in
+fn read_json_file<T>(
+    path: &PathBuf,
+    migrate: fn(Map<String, Value>) -> Map<String, Value>
+) -> Result<Option<(T, bool)>, String>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let json_string = match fs::read_to_string(path) {
+        Ok(json_string) => json_string,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("it couldn't be read ({})", err)),
+    };
+
+    let json = match serde_json::from_str::<Value>(&json_string) {
+        Ok(Value::Object(json)) => json,
+        Ok(_) => return Err("it isn't a JSON object".to_string()),
+        Err(err) => return Err(format!("it isn't valid JSON ({})", err)),
+    };
+    if json.is_empty() {
+        return Ok(None)
+    }
+
+    let value = serde_json::from_value::<T>(Value::Object(migrate(json.clone())))
+        .map_err(|err| format!("a value has the wrong type ({})", err))?;
+    let needs_saving = serde_json::to_value(&value).ok() != Some(Value::Object(json));
+
+    Ok(Some((value, needs_saving)))
+}
+
+// loads one of the files in the magnus directory. a missing, empty or unreadable file is replaced with the defaults
+fn load_json_file<T>(path: &PathBuf, migrate: fn(Map<String, Value>) -> Map<String, Value>) -> T
+where
+    T: Serialize + DeserializeOwned + Default,
+{
+    match read_json_file::<T>(path, migrate) {
+        Ok(Some((value, needs_saving))) => {
+            if needs_saving {
+                write_json_file(path, &value);
+            }
+            value
+        }
+        Ok(None) => {
+            println!("no {:?} file!!!", path);
+            let value = T::default();
+            write_json_file(path, &value);
+            value
+        }
+        Err(reason) => {
+            println!("Error loading {:?}: {}", path, reason);
+            backup_corrupt_file(path);
+            let value = T::default();
+            write_json_file(path, &value);
+            value
+        }
+    }
+}
+
 pub fn get_permissions_file_path() -> PathBuf {
     let mut path = tauri::api::path::data_dir().unwrap();
     path.push("magnus");
     path.push("permissions.json");
-    path    
+    path
 }
 
-pub fn create_permissions() {
-    let mut permissions_json = Map::new();
+// a Permission added since the file was written starts out denied, returns whether any were added
+fn add_missing_grants(permissions: &mut Permissions) -> bool {
+    let mut changed = false;
     for permission in Permission::iter() {
-        permissions_json.insert(permission.as_str().to_string(), Value::Bool(false));
+        if !permissions.grants.contains_key(permission.as_str()) {
+            permissions.grants.insert(permission.as_str().to_string(), PermissionGrant::new(PermissionMode::Deny, None));
+            changed = true;
+        }
     }
-    let pretty_json = to_string_pretty(&permissions_json).unwrap();
 
-    // create the magnus directory within the system's app data directory
-    match fs::create_dir(get_magnus_data_dir_path()) {
-        Ok(_) => println!("Created Directory"),
-        Err(err) => println!("Error Creating Directory: {}", err),
-    }
+    changed
+}
 
+fn load_permissions() -> Permissions {
+    let mut permissions: Permissions = load_json_file(&get_permissions_file_path(), migrate_permissions);
+    if add_missing_grants(&mut permissions) {
+        write_json_file(&get_permissions_file_path(), &permissions);
+    }
 
-    // create the permissions.json file with all false values
-    let _ = fs::write(get_permissions_file_path(), pretty_json.as_bytes()).expect("Failed to update permissions.json!");
+    permissions
 }
 
-pub fn update_permissions(permissions: Value) {
-    if permissions != Value::Object(Map::new()) {
-        let pretty_json = to_string_pretty(&permissions).unwrap();
-        let _ = fs::write(get_permissions_file_path(), pretty_json.as_bytes()).expect("Failed to update permissions.json!");
-    }
+pub fn get_permissions() -> Permissions {
+    PERMISSIONS.lock().unwrap().get_or_insert_with(load_permissions).clone()
 }
 
-pub fn get_permissions() -> Value {
-    match File::open(get_permissions_file_path()) {
-        Ok(mut file) => {
-            let mut json_string = String::new();
-            file.read_to_string(&mut json_string).expect("Failed to read permissions.json!");
-            let permissions: Value = serde_json::from_str(&json_string).expect("Failed to parse permissions.json!");
+// changes the permissions and saves them, no one else can change them in the meantime
+pub fn modify_permissions<F: FnOnce(&mut Permissions)>(modify: F) {
+    let grants = {
+        let mut cached = PERMISSIONS.lock().unwrap();
+        let permissions = cached.get_or_insert_with(load_permissions);
+        modify(permissions);
+        write_json_file(&get_permissions_file_path(), permissions);
+        permissions.grants.clone()
+    };
 
-            // fix empty permisisons file
-            if permissions == Value::Object(Map::new()) {
-                create_permissions();
-                return get_permissions()
-            }
+    emit_change("permissions-changed", grants);
+    wake_word::on_permissions_changed();
+}
 
-            return permissions    
-        },
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                println!("no permissions.json file!!!");
-                create_permissions();
-                return get_permissions()
-            } else {
-                todo!();
+// replaces grants with the ones the frontend sent, ignoring anything that isn't a known Permission or valid grant
+pub fn update_permission_grants(grants: &Map<String, Value>) {
+    modify_permissions(|permissions| {
+        for permission in Permission::iter() {
+            if let Some(grant) = grants.get(permission.as_str()).and_then(parse_permission_grant) {
+                permissions.grants.insert(permission.as_str().to_string(), grant);
             }
         }
+    })
+}
+
+// what Magnus may currently do with a permission
+pub fn get_permission_mode(permission: &Permission) -> PermissionMode {
+    if let Some(grant) = globals::get_session_permission_grant(permission.as_str()) {
+        if !grant.is_expired() {
+            return grant.get_mode()
+        }
+    }
+
+    get_permissions()
+        .grants
+        .get(permission.as_str())
+        .map(|grant| grant.get_mode())
+        .unwrap_or(PermissionMode::Deny)
+}
+
+// changes a permission, either in permissions.json or only until Magnus is closed. returns false if there is no such
+// permission
+pub fn grant_permission(
+    permission_name: &str,
+    mode: PermissionMode,
+    expires_in_seconds: Option<u64>,
+    session_only: bool
+) -> bool {
+    if !Permission::iter().any(|permission| permission.as_str() == permission_name) {
+        return false
+    }
+    let grant = PermissionGrant::new(mode, expires_in_seconds);
+
+    if session_only {
+        globals::set_session_permission_grant(permission_name.to_string(), grant);
+        wake_word::on_permissions_changed();
+    } else {
+        modify_permissions(|permissions| {
+            permissions.grants.insert(permission_name.to_string(), grant);
+        });
     }
+
+    println!("{} set to {:?}", permission_name, mode);
+    true
 }
 
-pub fn check_permissions(required: Vec<Permission>) -> Option<String> {
-    let permissions = get_permissions();
+// returns the required permissions that the user has to confirm first, or the ones that are denied
+pub fn check_permissions(required: Vec<Permission>) -> Result<Vec<Permission>, MagnusError> {
     let mut denied: Vec<Permission> = vec![];
+    let mut to_confirm: Vec<Permission> = vec![];
 
     for permission in required {
-        let granted = permissions.get(permission.as_str()).unwrap().as_bool().unwrap();
-
-        if !granted {
-            denied.push(permission.clone());
-            println!("no permission to {}", permission.as_str());
-        }
-        else {
-            println!("permission given for {}", permission.as_str());
+        match get_permission_mode(&permission) {
+            PermissionMode::Allow => {
+                println!("permission given for {}", permission.as_str());
+            }
+            PermissionMode::Ask => {
+                println!("permission needs confirming for {}", permission.as_str());
+                to_confirm.push(permission.clone());
+            }
+            PermissionMode::Deny => {
+                println!("no permission to {}", permission.as_str());
+                denied.push(permission.clone());
+            }
         }
     }
 
     if denied.len() == 0 {
-        return None
+        return Ok(to_confirm)
     }
     else {
-        let all_denied: Vec<&str> = denied.iter().map(|p| p.as_str()).collect();
-
-        // this message could potentially use tweaking
-        return Some(format!("You MUST tell the user they need to allow access to ALL of the following features in settings: {}", all_denied.join(", ")))
+        let all_denied: Vec<String> = denied.iter().map(|p| p.as_str().to_string()).collect();
+        return Err(MagnusError::PermissionDenied(all_denied))
     }
 }
 
@@ -124,54 +492,214 @@ pub fn get_settings_file_path() -> PathBuf {
     let mut path = tauri::api::path::data_dir().unwrap();
     path.push("magnus");
     path.push("settings.json");
-    path    
+    path
 }
 
-pub fn create_settings() {
-    let settings_json = serde_json::json!({
-        "audioInputDeviceSelection": audio_input::get_default_audio_input_device().name().unwrap(),
-        "audioOutputDeviceSelection": audio_output::get_default_audio_output_device().name().unwrap()
-    }).as_object().unwrap().clone();
+// a settings.json that doesn't pass validate_settings is treated like one that can't be read. problems with the hotkeys
+// aren't, register_hotkeys registers what it can and reports the rest rather than losing every other setting over them
+fn load_settings() -> Settings {
+    let path = get_settings_file_path();
+    let settings: Settings = load_json_file(&path, migrate_settings);
 
-    let pretty_json = to_string_pretty(&settings_json).unwrap();
+    match validate_settings(&settings) {
+        Ok(_) => settings,
+        Err(reason) => {
+            println!("Error loading {:?}: {}, using the default settings", path, reason);
+            backup_corrupt_file(&path);
+            let settings = Settings::default();
+            write_json_file(&path, &settings);
+            settings
+        }
+    }
+}
 
-    // create the magnus directory within the system's app data directory
-    let _ = fs::create_dir(get_magnus_data_dir_path());
+pub fn get_settings() -> Settings {
+    SETTINGS.lock().unwrap().get_or_insert_with(load_settings).clone()
+}
 
-    let _ = fs::write(get_settings_file_path(), pretty_json.as_bytes()).expect("Failed to update settings.json!");
+// changes the settings and saves them, no one else can change them in the meantime
+pub fn modify_settings<F: FnOnce(&mut Settings)>(modify: F) {
+    let (settings, previous_settings) = {
+        let mut cached = SETTINGS.lock().unwrap();
+        let settings = cached.get_or_insert_with(load_settings);
+        let previous_settings = settings.clone();
+        modify(settings);
+        write_json_file(&get_settings_file_path(), settings);
+        (settings.clone(), previous_settings)
+    };
+
+    apply_settings_changes(Some(&previous_settings), &settings);
+    emit_change("settings-changed", settings);
 }
 
-pub fn update_settings(settings: Value) {
-    if settings != Value::Object(Map::new()) {
-        let pretty_json = to_string_pretty(&settings).unwrap();
-        let _ = fs::write(get_settings_file_path(), pretty_json.as_bytes()).expect("Failed to update settings.json!");
-   }
+// re-registers the hotkeys and restarts wake word listening when their settings change. the settings lock has to be
+// released first, both read the settings
+fn apply_settings_changes(previous_settings: Option<&Settings>, settings: &Settings) {
+    if previous_settings.map_or(true, |previous| previous.hotkeys != settings.hotkeys) {
+        let app_handle = APP_HANDLE.lock().unwrap().clone();
+        if let Some(app_handle) = app_handle {
+            hotkeys::register_hotkeys(&app_handle);
+        }
+    }
+
+    if previous_settings.map_or(true, |previous| {
+        previous.wake_word_enabled != settings.wake_word_enabled || previous.wake_phrase != settings.wake_phrase
+    }) {
+        wake_word::update_wake_word_listener();
+    }
 }
 
-pub fn get_settings() -> Value {
-    match File::open(get_settings_file_path()) {
-        Ok(mut file) => {
-            let mut json_string = String::new();
-            file.read_to_string(&mut json_string).expect("Failed to read settings.json!");
-            let settings: Value = serde_json::from_str(&json_string).expect("Failed to parse settings.json!");
+// the base URL of the OpenAI-compatible API, lets Magnus talk to a local server such as Ollama or llama.cpp
+pub fn get_api_base_url() -> String {
+    get_settings().api_base_url.trim_end_matches('/').to_string()
+}
 
-            // fix empty settings file
-            if settings == Value::Object(Map::new()) {
-                create_settings();
-                return get_settings()
+// the checks a hand edited settings.json has to pass on top of having the right types
+fn validate_settings(settings: &Settings) -> Result<(), String> {
+    if !["assistants", "chatCompletions"].contains(&settings.chat_backend.as_str()) {
+        return Err(format!("\"{}\" isn't a chat backend, use \"assistants\" or \"chatCompletions\"", settings.chat_backend))
+    }
+    if !settings.api_base_url.starts_with("http://") && !settings.api_base_url.starts_with("https://") {
+        return Err(format!("\"{}\" isn't an http(s) URL", settings.api_base_url))
+    }
+    if settings.request_timeout_seconds == 0 {
+        return Err("requestTimeoutSeconds has to be more than 0".to_string())
+    }
+    if settings.no_speech_timeout_ms == 0 || settings.no_audio_timeout_ms == 0 || settings.end_of_utterance_silence_ms == 0 {
+        return Err("noSpeechTimeoutMs, noAudioTimeoutMs and endOfUtteranceSilenceMs have to be more than 0".to_string())
+    }
+    let device_configs = settings.audio_input_device_configs.iter().chain(settings.audio_output_device_configs.iter());
+    for (device_name, config) in device_configs {
+        if config.sample_rate == Some(0) || config.channels == Some(0) || config.buffer_size == Some(0) {
+            return Err(format!("the audio config for \"{}\" has a value of 0", device_name))
+        }
+    }
+    if settings.wake_word_enabled && settings.wake_phrase.trim().is_empty() {
+        return Err("wakePhrase can't be empty while wakeWordEnabled is on".to_string())
+    }
+
+    Ok(())
+}
+
+/*
+Power users edit settings.json and permissions.json by hand while Magnus is running. The watcher reloads a file when it
+changes and lets the frontend know, the same as a change made through modify_settings or modify_permissions.
+
+An edit that can't be read, doesn't parse or fails validation is rejected with the reason logged, and the last good
+values stay in use. Unlike at startup the file isn't replaced, since the user is probably still working on it. Our own
+writes trigger the watcher as well, those are skipped because they match what's already in memory.
+*/
+pub fn watch_settings_files() {
+    // make sure both files and the directory exist before watching them
+    let _ = get_settings();
+    let _ = get_permissions();
+
+    thread::spawn(|| {
+        let (sender, receiver) = mpsc::channel::<notify::Result<notify::Event>>();
+        let mut watcher = match notify::recommended_watcher(sender) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                println!("Error creating settings watcher: {}", err);
+                return
             }
+        };
+        // the directory is watched since files are replaced rather than written to
+        if let Err(err) = watcher.watch(&get_magnus_data_dir_path(), RecursiveMode::NonRecursive) {
+            println!("Error watching {:?}: {}", get_magnus_data_dir_path(), err);
+            return
+        }
 
-            return settings    
-        },
-        Err(err) => {
-            if err.kind() == std::io::ErrorKind::NotFound {
-                println!("no settings.json file!!!");
-                create_settings();
-                return get_settings()
+        while let Ok(event) = receiver.recv() {
+            let mut paths = match event {
+                Ok(event) => event.paths,
+                Err(err) => {
+                    println!("Settings watcher error: {}", err);
+                    continue
+                }
+            };
+
+            // editors often save with several writes in a row, wait for them to finish and handle them together
+            thread::sleep(Duration::from_millis(200));
+            while let Ok(event) = receiver.try_recv() {
+                if let Ok(event) = event {
+                    paths.extend(event.paths);
+                }
             }
-            else {
-                todo!()
+
+            let changed = |file_name: &str| paths.iter().any(|path| path.file_name().map_or(false, |name| name == file_name));
+            if changed("settings.json") {
+                reload_settings();
             }
+            if changed("permissions.json") {
+                reload_permissions();
+            }
+        }
+    });
+}
+
+// reads a file that was changed outside of Magnus, None if the change should be ignored
+fn read_edited_file<T>(path: &PathBuf, migrate: fn(Map<String, Value>) -> Map<String, Value>) -> Option<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    match read_json_file::<T>(path, migrate) {
+        Ok(Some((value, _))) => Some(value),
+        Ok(None) => {
+            println!("{:?} was removed or emptied, keeping the current values", path);
+            None
+        }
+        Err(reason) => {
+            println!("Rejected the edit to {:?} because {}", path, reason);
+            None
+        }
+    }
+}
+
+fn reload_settings() {
+    let settings: Settings = match read_edited_file(&get_settings_file_path(), migrate_settings) {
+        Some(settings) => settings,
+        None => return,
+    };
+    if let Err(reason) = validate_settings(&settings) {
+        println!("Rejected the edit to settings.json because {}", reason);
+        return
+    }
+    // unlike at startup nothing is lost by rejecting an edit, so the user gets to fix the hotkeys first
+    let (_, hotkey_problems) = hotkeys::check_hotkeys(&settings.hotkeys);
+    if !hotkey_problems.is_empty() {
+        println!("Rejected the edit to settings.json because {}", hotkey_problems.join(", "));
+        return
+    }
+
+    let previous_settings = {
+        let mut cached = SETTINGS.lock().unwrap();
+        if cached.as_ref().and_then(|cached| serde_json::to_value(cached).ok()) == serde_json::to_value(&settings).ok() {
+            return
         }
+        cached.replace(settings.clone())
+    };
+
+    println!("Reloaded settings.json");
+    apply_settings_changes(previous_settings.as_ref(), &settings);
+    emit_change("settings-changed", settings);
+}
+
+fn reload_permissions() {
+    let mut permissions: Permissions = match read_edited_file(&get_permissions_file_path(), migrate_permissions) {
+        Some(permissions) => permissions,
+        None => return,
+    };
+    add_missing_grants(&mut permissions);
+
+    {
+        let mut cached = PERMISSIONS.lock().unwrap();
+        if cached.as_ref().and_then(|cached| serde_json::to_value(cached).ok()) == serde_json::to_value(&permissions).ok() {
+            return
+        }
+        *cached = Some(permissions.clone());
     }
+
+    println!("Reloaded permissions.json");
+    emit_change("permissions-changed", permissions.grants);
+    wake_word::on_permissions_changed();
 }
@@ -1,7 +1,10 @@
+use crate::confirmation::request_confirmation;
+use crate::error::MagnusError;
 use crate::globals::{
     get_ip_api_key, get_opencage_key, get_reqwest_client, get_weather_api_user_agent,
 };
-use crate::settings::{check_permissions, Permission, Permission::*};
+use crate::request::send_with_retry;
+use crate::settings::{check_permissions, get_settings, Permission, Permission::*};
 use crate::{Payload, APP_HANDLE};
 use base64::prelude::{Engine as _, BASE64_STANDARD_NO_PAD};
 use chrono::prelude::Local;
@@ -15,7 +18,8 @@ use scrap::{Capturer, Display};
 use serde_json::{Map, Value};
 use tauri::Manager;
 use std::{
-    fs::File, future::Future, io::ErrorKind::WouldBlock, path::Path, pin::Pin, sync::Arc, thread::sleep, time::Duration
+    future::Future, io::ErrorKind::WouldBlock, pin::Pin, sync::Arc, thread::sleep,
+    time::{Duration, Instant},
 };
 use urlencoding::encode;
 
@@ -27,7 +31,7 @@ then name the arg in the function signature as an underscore.
 Traits:
 Send - this is needed so that the Tool can be transferred to another thread
 Sync - this is needed so that the Tool can be access from multiple threads
-Without these two traits, we are unable to define the Tools as public static references and use them in assistant.rs
+Without these two traits, we are unable to keep the Tools in the static TOOL_REGISTRY and use them in assistant.rs
 
 AsyncAction returns a Future that results in a String, which must be wrapped in a Box since the size of the Future in
 memory is not statically known, it can vary. Wrapping the Future in a Box creates the Future in heap-space, where the
@@ -42,42 +46,88 @@ pub enum Action {
 }
 
 pub struct Tool {
+    pub name: String, // the name the model calls the tool by
+    pub purpose: String, // tells the model what the tool does, so it knows when to use it
+    pub parameters: Value, // JSON Schema of the args the action expects
     pub action: Action, // the function that runs when Magnus uses the tool
     pub description: String, // the message that is displayed on the frontend when Magnus uses the tool
     pub permissions: Option<Vec<Permission>> // the list of Permissions needed to execute the tool
 }
 
 impl Tool {
-    pub fn new_sync<F>(action: F, description: String, permissions: Option<Vec<Permission>>) -> Self
+    pub fn new_sync<F>(
+        name: &str,
+        purpose: &str,
+        parameters: Value,
+        action: F,
+        description: &str,
+        permissions: Option<Vec<Permission>>,
+    ) -> Self
     where
         F: Fn(Map<String, Value>) -> String + Send + Sync + 'static,
     {
         Tool {
+            name: name.to_string(),
+            purpose: purpose.to_string(),
+            parameters,
             action: Action::Sync(Arc::new(action)),
-            description: description,
-            permissions: permissions
+            description: description.to_string(),
+            permissions,
         }
     }
 
-    pub fn new_async<F, Fut>(action: F, description: String, permissions: Option<Vec<Permission>>) -> Self
+    pub fn new_async<F, Fut>(
+        name: &str,
+        purpose: &str,
+        parameters: Value,
+        action: F,
+        description: &str,
+        permissions: Option<Vec<Permission>>,
+    ) -> Self
     where
         F: Fn(Map<String, Value>) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = String> + Send + 'static,
     {
         Tool {
+            name: name.to_string(),
+            purpose: purpose.to_string(),
+            parameters,
             action: Action::Async(Arc::new(move |args| Box::pin(action(args)))),
-            description: description,
-            permissions: permissions
+            description: description.to_string(),
+            permissions,
         }
     }
 
-    pub async fn execute(&self, args: Map<String, Value>) -> String {
-        // check if all permissions are satisfied
-        if let Some(permissions) = &self.permissions {
-            if let Some(result) = check_permissions(permissions.to_vec()) {
-                println!("got result !!! {}", result.clone());
-                return result
+    // the function definition the model is given for this tool
+    pub fn get_definition(&self) -> Value {
+        serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.purpose,
+                "parameters": self.parameters
             }
+        })
+    }
+
+    pub async fn execute(&self, args: Map<String, Value>) -> Result<String, MagnusError> {
+        // check if all permissions are satisfied, some may need the user to confirm this particular use
+        let mut to_confirm: Vec<Permission> = vec![];
+        if let Some(permissions) = &self.permissions {
+            to_confirm = check_permissions(permissions.to_vec())?;
+        }
+
+        // the model gets told what was wrong with its args so it can call the tool again
+        let problems = validate_args(&self.parameters, &args);
+        if !problems.is_empty() {
+            println!("invalid args for {}: {:?}", self.name, problems);
+            return Ok(get_invalid_args_output(&self.name, &problems));
+        }
+
+        if !to_confirm.is_empty()
+            && !request_confirmation(&self.name, &self.description, &args, &to_confirm).await
+        {
+            return Err(MagnusError::ActionDenied(self.name.clone()));
         }
 
         // TODO: emit the description to the frontend
@@ -87,34 +137,250 @@ impl Tool {
         }
         println!("**{}...**", &self.description);
 
+        // the deadline only covers the action itself, not the time spent waiting for the user to confirm it
+        let timeout = get_tool_timeout(&self.name);
+        let timed_out = || MagnusError::ToolTimedOut(self.name.clone(), timeout.as_secs());
         match &self.action {
-            Action::Sync(action) => action(args),
-            Action::Async(action) => action(args).await,
+            /*
+            sync actions block, so they run on their own thread where the deadline can still be checked. a thread can't
+            be stopped from the outside, so one that times out is left to finish in the background and its output is
+            dropped, but the turn moves on without it. one that panics is reported like any other failed tool
+            */
+            Action::Sync(action) => {
+                let action = action.clone();
+                let handle = tokio::task::spawn_blocking(move || action(args));
+                match tokio::time::timeout(timeout, handle).await {
+                    Ok(Ok(output)) => Ok(output),
+                    Ok(Err(err)) => {
+                        println!("{} failed: {}", self.name, err);
+                        Err(MagnusError::ToolFailed(self.name.clone(), "it stopped unexpectedly".to_string()))
+                    }
+                    Err(_) => Err(timed_out()),
+                }
+            }
+            Action::Async(action) => tokio::time::timeout(timeout, action(args)).await.map_err(|_| timed_out()),
+        }
+    }
+}
+
+const DEFAULT_TOOL_TIMEOUT_SECONDS: u64 = 30;
+
+// how long a tool may run before the model is told it timed out. configurable per tool through "toolTimeoutSeconds"
+// in settings.json, e.g. { "SCREENSHOT": 10, "default": 20 }
+pub fn get_tool_timeout(tool: &str) -> Duration {
+    let timeouts = get_settings().tool_timeout_seconds;
+    let seconds = timeouts
+        .get(tool)
+        .or_else(|| timeouts.get("default"))
+        .copied()
+        .unwrap_or(DEFAULT_TOOL_TIMEOUT_SECONDS);
+
+    Duration::from_secs(seconds)
+}
+
+// every Tool Magnus can use, the function definitions sent to the model are generated from these
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry { tools: vec![] }
+    }
+
+    pub fn register(&mut self, tool: Tool) {
+        if self.get(&tool.name).is_some() {
+            panic!("A tool named {} is already registered!", tool.name);
+        }
+        self.tools.push(tool);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tool> {
+        self.tools.iter().find(|tool| tool.name == name)
+    }
+
+    pub fn get_definitions(&self) -> Value {
+        Value::Array(self.tools.iter().map(Tool::get_definition).collect())
+    }
+}
+
+// the output given to the model in place of running a tool it called with bad args
+pub fn get_invalid_args_output(tool: &str, problems: &[String]) -> String {
+    serde_json::json!({
+        "error": "invalid_arguments",
+        "tool": tool,
+        "problems": problems,
+        "instructions": "Call the tool again with arguments that match its parameters."
+    })
+    .to_string()
+}
+
+// checks args against the parts of JSON Schema the tool parameters use, returning every problem found
+fn validate_args(schema: &Value, args: &Map<String, Value>) -> Vec<String> {
+    let mut problems: Vec<String> = vec![];
+    let properties = schema["properties"].as_object();
+
+    if let Some(required) = schema["required"].as_array() {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !args.contains_key(name) {
+                problems.push(format!("missing required argument \"{}\"", name));
+            }
+        }
+    }
+
+    for (name, value) in args {
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property) => validate_value(name, property, value, &mut problems),
+            None => {
+                if schema["additionalProperties"] == false {
+                    problems.push(format!("unexpected argument \"{}\"", name));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+fn validate_value(name: &str, schema: &Value, value: &Value, problems: &mut Vec<String>) {
+    if let Some(expected_type) = schema["type"].as_str() {
+        let is_expected_type = match expected_type {
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+
+        if !is_expected_type {
+            problems.push(format!(
+                "argument \"{}\" must be of type {}, got {}",
+                name, expected_type, value
+            ));
+            return;
+        }
+    }
+
+    if let Some(options) = schema["enum"].as_array() {
+        if !options.contains(value) {
+            problems.push(format!(
+                "argument \"{}\" must be one of {}, got {}",
+                name, schema["enum"], value
+            ));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema["minimum"].as_f64() {
+            if number < minimum {
+                problems.push(format!("argument \"{}\" must be at least {}", name, minimum));
+            }
+        }
+        if let Some(maximum) = schema["maximum"].as_f64() {
+            if number > maximum {
+                problems.push(format!("argument \"{}\" must be at most {}", name, maximum));
+            }
         }
     }
 }
 
-// create Tools here to be exposed in assistant.rs
+// schema for tools whose action ignores its args
+fn no_parameters() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+// register Tools here, adding one here is all it takes for Magnus to be able to use it
 lazy_static! {
-    pub static ref CLIPBOARD: Tool = Tool::new_sync(get_clipboard_text, "Peeking at your clipboard".to_string(), Some(vec![Clipboard]));
-    pub static ref FORECAST: Tool = Tool::new_async(get_forecast, "Checking the radar".to_string(), None);
-    pub static ref LOCATION_COORDINATES: Tool = Tool::new_async(get_location_coordinates, "Looking at the map".to_string(), None);
-    pub static ref SCREENSHOT: Tool = Tool::new_async(get_screenshot, "Peeking at your screen".to_string(), Some(vec![Screenshot]));
-    pub static ref TIME: Tool = Tool::new_sync(get_time, "Checking wrist watch".to_string(), None);
-    pub static ref USER_COORDINATES: Tool = Tool::new_async(get_user_coordinates, "Accessing your location".to_string(), Some(vec![Location]));
+    static ref TOOL_REGISTRY: ToolRegistry = {
+        let mut registry = ToolRegistry::new();
+
+        registry.register(Tool::new_sync(
+            "CLIPBOARD",
+            "Gets the text currently copied to the user's clipboard.",
+            no_parameters(),
+            get_clipboard_text,
+            "Peeking at your clipboard",
+            Some(vec![Clipboard]),
+        ));
+        registry.register(Tool::new_async(
+            "FORECAST",
+            "Gets the weather forecast for the given coordinates, only works within the USA.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "latitude": { "type": "number", "minimum": -90, "maximum": 90, "description": "Latitude of the location." },
+                    "longitude": { "type": "number", "minimum": -180, "maximum": 180, "description": "Longitude of the location." },
+                    "n_days": { "type": "integer", "minimum": 1, "maximum": 7, "description": "Number of days to forecast." }
+                },
+                "required": ["latitude", "longitude", "n_days"]
+            }),
+            get_forecast,
+            "Checking the radar",
+            None,
+        ));
+        registry.register(Tool::new_async(
+            "LOCATION_COORDINATES",
+            "Gets the latitude and longitude of a named location.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "location": { "type": "string", "description": "The name of the location, e.g. Chicago, IL." }
+                },
+                "required": ["location"]
+            }),
+            get_location_coordinates,
+            "Looking at the map",
+            None,
+        ));
+        registry.register(Tool::new_async(
+            "SCREENSHOT",
+            "Takes a screenshot of the user's primary display, returned as a base64 encoded PNG.",
+            no_parameters(),
+            get_screenshot,
+            "Peeking at your screen",
+            Some(vec![Screenshot]),
+        ));
+        registry.register(Tool::new_sync(
+            "TIME",
+            "Gets the user's current local date and time.",
+            no_parameters(),
+            get_time,
+            "Checking wrist watch",
+            None,
+        ));
+        registry.register(Tool::new_async(
+            "USER_COORDINATES",
+            "Gets the latitude and longitude of the user's current location.",
+            no_parameters(),
+            get_user_coordinates,
+            "Accessing your location",
+            Some(vec![Location]),
+        ));
+
+        registry
+    };
 }
 
+pub fn get_tool_registry() -> &'static ToolRegistry {
+    &TOOL_REGISTRY
+}
+
+// args are checked against the tool's parameters before any action runs, but the actions still shouldn't panic
 pub async fn get_location_coordinates(args: Map<String, Value>) -> String {
-    let location = args.get("location").unwrap().as_str().unwrap();
-
-    let coordinates_result = get_reqwest_client()
-        .get(format!(
-            "https://api.opencagedata.com/geocode/v1/json?key={}&q={}",
-            get_opencage_key(),
-            encode(location)
-        ))
-        .send()
-        .await;
+    let location = args.get("location").and_then(Value::as_str).unwrap_or_default();
+
+    let coordinates_result = send_with_retry(
+        get_reqwest_client()
+            .get(format!(
+                "https://api.opencagedata.com/geocode/v1/json?key={}&q={}",
+                get_opencage_key(),
+                encode(location)
+            )),
+    )
+    .await;
     
     match coordinates_result {
         Ok(coordinates_response) => match coordinates_response.json::<Value>().await {
@@ -132,15 +398,16 @@ pub async fn get_location_coordinates(args: Map<String, Value>) -> String {
 }
 
 pub async fn get_forecast(args: Map<String, Value>) -> String {
-    let lat = &args.get("latitude").unwrap().to_string();
-    let lng = &args.get("longitude").unwrap().to_string();
-    let n_days = &args.get("n_days").unwrap().to_string();
+    let lat = &args.get("latitude").cloned().unwrap_or_default().to_string();
+    let lng = &args.get("longitude").cloned().unwrap_or_default().to_string();
+    let n_days = args.get("n_days").and_then(Value::as_u64).unwrap_or(1) as usize;
 
-    let weather_result = get_reqwest_client()
-        .get(format!("https://api.weather.gov/points/{},{}", lat, lng))
-        .header("User-Agent", get_weather_api_user_agent())
-        .send()
-        .await;
+    let weather_result = send_with_retry(
+        get_reqwest_client()
+            .get(format!("https://api.weather.gov/points/{},{}", lat, lng))
+            .header("User-Agent", get_weather_api_user_agent()),
+    )
+    .await;
 
     match weather_result {
         Ok(weather_response) => {
@@ -150,11 +417,12 @@ pub async fn get_forecast(args: Map<String, Value>) -> String {
                         .to_string()
                         .trim_matches('"')
                         .to_string();
-                    let forecast_result = get_reqwest_client()
-                        .get(forecast_url)
-                        .header("User-Agent", get_weather_api_user_agent())
-                        .send()
-                        .await;
+                    let forecast_result = send_with_retry(
+                        get_reqwest_client()
+                            .get(forecast_url)
+                            .header("User-Agent", get_weather_api_user_agent()),
+                    )
+                    .await;
 
                     match forecast_result {
                         Ok(forecast_response) => {
@@ -163,10 +431,9 @@ pub async fn get_forecast(args: Map<String, Value>) -> String {
                                     match forecast["properties"]["periods"].as_array() {
                                         Some(days) => {
                                             let mut the_forecast = "".to_string();
-                                            let mut num_days = n_days.parse().unwrap();
-                                            num_days *= 2; // because we receive forecast in half days, and assistants gives us n days
-                                            for day in 0..num_days {
-                                                match days[day].as_object() {
+                                            let num_days = n_days * 2; // because we receive forecast in half days, and assistants gives us n days
+                                            for day in days.iter().take(num_days) {
+                                                match day.as_object() {
                                                     Some(day_forecast) => {
                                                         the_forecast.push_str(
                                                             format!(
@@ -202,10 +469,11 @@ pub async fn get_forecast(args: Map<String, Value>) -> String {
 }
 
 pub async fn get_user_coordinates(_: Map<String, Value>) -> String {
-    let user_coordinates_result = get_reqwest_client()
-        .get(format!("https://ipapi.co/json/?key={}", get_ip_api_key()))
-        .send()
-        .await;
+    let user_coordinates_result = send_with_retry(
+        get_reqwest_client()
+            .get(format!("https://ipapi.co/json/?key={}", get_ip_api_key())),
+    )
+    .await;
 
     match user_coordinates_result {
         Ok(user_coordinates_response) => match user_coordinates_response.json::<Value>().await {
@@ -223,31 +491,54 @@ pub async fn get_user_coordinates(_: Map<String, Value>) -> String {
 
 // returns the contents of the systems clipboard
 pub fn get_clipboard_text(_: Map<String, Value>) -> String {
-    let mut clipboard = ClipboardContext::new().unwrap();
+    let mut clipboard = match ClipboardContext::new() {
+        Ok(clipboard) => clipboard,
+        Err(error) => return format!("Error accessing the clipboard: {}", error),
+    };
     match clipboard.get_contents() {
         Ok(text) => text,
-        Err(error) => {
-            panic!("Error getting clipboard contents: {}", error);
-        },
+        Err(error) => format!("Error getting clipboard contents: {}", error),
     }
 }
 
+// how long to keep waiting for the display to hand over a frame
+const SCREENSHOT_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
 // returns a string representation of a base64 screenshot of the primary display
 pub async fn get_screenshot(_: Map<String, Value>) -> String {
-    let display = Display::primary().expect("Couldn't find primary display.");
-    let width: u32 = display.width().try_into().unwrap();
-    let height: u32 = display.height().try_into().unwrap();
-    let mut capturer = Capturer::new(display).expect("Couldn't begin capture.");
+    // capturing waits on the display, which would block every other tool and request on this thread
+    match tokio::task::spawn_blocking(capture_screenshot).await {
+        Ok(Ok(screenshot)) => screenshot,
+        Ok(Err(err)) => err.to_string(),
+        Err(e) => format!("Couldn't capture the screen: {}", e),
+    }
+}
+
+fn get_screenshot_error(details: String) -> MagnusError {
+    MagnusError::ToolFailed("SCREENSHOT".to_string(), details)
+}
+
+// the primary display as a base64 encoded PNG
+fn capture_screenshot() -> Result<String, MagnusError> {
+    let display = Display::primary().map_err(|e| get_screenshot_error(format!("couldn't find primary display: {}", e)))?;
+    let width = display.width() as u32;
+    let height = display.height() as u32;
+    let mut capturer =
+        Capturer::new(display).map_err(|e| get_screenshot_error(format!("couldn't begin capture: {}", e)))?;
 
+    let start_time = Instant::now();
     loop {
         // wait for a frame
         let buffer = match capturer.frame() {
             Ok(buffer) => buffer,
             Err(e) if e.kind() == WouldBlock => {
+                if start_time.elapsed() > SCREENSHOT_FRAME_TIMEOUT {
+                    return Err(get_screenshot_error("the display never produced a frame".to_string()));
+                }
                 sleep(Duration::from_millis(100));
                 continue;
             }
-            Err(e) => panic!("Error: {}", e),
+            Err(e) => return Err(get_screenshot_error(format!("couldn't capture the screen: {}", e))),
         };
 
         // convert the image data to an image
@@ -266,20 +557,10 @@ pub async fn get_screenshot(_: Map<String, Value>) -> String {
         let mut bytes: Vec<u8> = Vec::new();
         PngEncoder::new(&mut bytes)
             .write_image(&resized_img, new_width, new_height, Rgba8)
-            .unwrap();
-
-        // encode the image data to base64
-        let base64_image = &BASE64_STANDARD_NO_PAD.encode(&bytes);
-
-        // write image for now, for viewing purposes. this will be removed later
-        let path = Path::new("C:/Users/schre/Projects/screenshot.png");
-        let file = File::create(path).expect("Couldn't create output file.");
-        PngEncoder::new(file)
-            .write_image(&resized_img, new_width, new_height, Rgba8)
-            .expect("Couldn't encode frame.");
+            .map_err(|e| get_screenshot_error(format!("couldn't encode the frame: {}", e)))?;
 
-        // only need one frame
-        return base64_image.to_string();
+        // encode the image data to base64, only need one frame
+        return Ok(BASE64_STANDARD_NO_PAD.encode(&bytes));
     }
 }
 
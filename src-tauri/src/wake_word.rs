@@ -0,0 +1,275 @@
+use crate::audio_input::{self, run_transcription, VoiceActivity, VoiceActivityDetector};
+use crate::error::MagnusError;
+use crate::settings::{get_permission_mode, get_settings, Permission, PermissionMode};
+use crate::{globals, globals::get_vosk_model, hotkeys, Payload, APP_HANDLE};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use cpal::SampleRate;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::Manager;
+use tokio_util::sync::CancellationToken;
+use vosk::{DecodingState, Recognizer};
+
+/*
+With "wakeWordEnabled" in settings.json Magnus listens all the time for "wakePhrase" ("hey magnus" unless it's
+changed), and once it hears it listens for a question the same way as after Alt+M.
+
+Spotting the phrase uses a recognizer restricted to a grammar of only the phrase, so everything else is heard as [unk]
+and it takes a fraction of the work of full transcription. The voice activity detector keeps silence away from it
+entirely, so a quiet room costs next to nothing. Listening pauses while a turn is running and while Magnus is speaking,
+so it doesn't hear its own answers, and it never starts unless the Microphone permission is allowed outright.
+
+The frontend is told about every change with a "listening-state" event.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListeningState {
+    Off, // wake word listening is turned off
+    WaitingForWakePhrase,
+    Listening, // the wake phrase was heard, listening for the question
+    Paused, // a turn is running or Magnus is speaking
+    NotAllowed, // the Microphone permission isn't allowed
+}
+
+impl ListeningState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListeningState::Off => "off",
+            ListeningState::WaitingForWakePhrase => "waitingForWakePhrase",
+            ListeningState::Listening => "listening",
+            ListeningState::Paused => "paused",
+            ListeningState::NotAllowed => "notAllowed",
+        }
+    }
+}
+
+// how long to wait before checking again whether listening can resume
+const WAKE_WORD_RECHECK_INTERVAL: Duration = Duration::from_millis(250);
+// how long to wait before opening the microphone again after it failed, doubling each time it fails again in a row
+const WAKE_WORD_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+const WAKE_WORD_MAX_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// the wake phrase is short, so a short pause is enough to end it
+const WAKE_PHRASE_HANGOVER: Duration = Duration::from_millis(300);
+
+lazy_static! {
+    // stops the listener thread that's running, if there is one
+    static ref WAKE_WORD_LISTENER: Mutex<Option<CancellationToken>> = Mutex::new(None);
+
+    static ref LISTENING_STATE: Mutex<ListeningState> = Mutex::new(ListeningState::Off);
+
+    // how many turns and answers are keeping the listener paused
+    static ref WAKE_WORD_PAUSES: Mutex<u32> = Mutex::new(0);
+}
+
+pub fn get_listening_state() -> ListeningState {
+    *LISTENING_STATE.lock().unwrap()
+}
+
+fn set_listening_state(state: ListeningState) {
+    {
+        let mut listening_state = LISTENING_STATE.lock().unwrap();
+        if *listening_state == state {
+            return;
+        }
+        *listening_state = state;
+    }
+
+    println!("Wake word listening: {}", state.as_str());
+    let app_handle = APP_HANDLE.lock().unwrap().clone();
+    if let Some(app_handle) = app_handle {
+        let _ = app_handle.emit_all(
+            "listening-state",
+            Payload {
+                message: state.as_str().to_string(),
+            },
+        );
+    }
+}
+
+// keeps the listener paused for as long as it's held, e.g. while a turn is running
+pub struct WakeWordPause;
+
+pub fn pause_wake_word() -> WakeWordPause {
+    *WAKE_WORD_PAUSES.lock().unwrap() += 1;
+    WakeWordPause
+}
+
+impl Drop for WakeWordPause {
+    fn drop(&mut self) {
+        *WAKE_WORD_PAUSES.lock().unwrap() -= 1;
+    }
+}
+
+fn is_paused() -> bool {
+    *WAKE_WORD_PAUSES.lock().unwrap() > 0
+}
+
+// starts or stops listening for the wake phrase to match settings.json, restarting it if the phrase changed
+pub fn update_wake_word_listener() {
+    let mut listener = WAKE_WORD_LISTENER.lock().unwrap();
+    if let Some(stop_token) = listener.take() {
+        stop_token.cancel();
+    }
+
+    let settings = get_settings();
+    if !settings.wake_word_enabled {
+        set_listening_state(ListeningState::Off);
+        return;
+    }
+
+    let stop_token = CancellationToken::new();
+    *listener = Some(stop_token.clone());
+    thread::spawn(move || run_wake_word_listener(settings.wake_phrase.trim().to_lowercase(), stop_token));
+}
+
+// starts listening again if it stopped because the Microphone permission wasn't allowed and now it is
+pub fn on_permissions_changed() {
+    if get_listening_state() == ListeningState::NotAllowed
+        && get_permission_mode(&Permission::Microphone) == PermissionMode::Allow
+    {
+        update_wake_word_listener();
+    }
+}
+
+fn run_wake_word_listener(wake_phrase: String, stop_token: CancellationToken) {
+    let mut retry_interval = WAKE_WORD_RETRY_INTERVAL;
+    loop {
+        if stop_token.is_cancelled() {
+            return;
+        }
+
+        if is_paused() {
+            set_listening_state(ListeningState::Paused);
+            thread::sleep(WAKE_WORD_RECHECK_INTERVAL);
+            continue;
+        }
+
+        /*
+        Without the Microphone permission there is nothing to do until it changes, so the listener stops rather than
+        checking again every few seconds. on_permissions_changed starts a new one once it's allowed. The state is set
+        before checking again, so a permission allowed in between is either seen here or finds the listener NotAllowed.
+        */
+        if get_permission_mode(&Permission::Microphone) != PermissionMode::Allow {
+            set_listening_state(ListeningState::NotAllowed);
+            if get_permission_mode(&Permission::Microphone) != PermissionMode::Allow {
+                return;
+            }
+            continue;
+        }
+
+        set_listening_state(ListeningState::WaitingForWakePhrase);
+        let listener_stop_token = stop_token.clone();
+        let listener_wake_phrase = wake_phrase.clone();
+        let captured = audio_input::capture(move |audio_input_receiver, sample_rate| -> Result<_, MagnusError> {
+            if !wait_for_wake_phrase(&audio_input_receiver, sample_rate, &listener_wake_phrase, &listener_stop_token) {
+                return Ok(None);
+            }
+            set_listening_state(ListeningState::Listening);
+
+            // the question is transcribed from the same stream the phrase was heard on, so nothing said right after
+            // the phrase is lost
+            let cancel_token = CancellationToken::new();
+            globals::set_cancel_token(cancel_token.clone());
+            let capture_start_time = Instant::now();
+            let (transcription, end_reason) = run_transcription(audio_input_receiver, sample_rate, cancel_token, None)?;
+            audio_input::emit_capture_ended(end_reason, &transcription, capture_start_time);
+
+            Ok(transcription)
+        })
+        .and_then(|transcription| transcription);
+
+        if captured.is_ok() {
+            retry_interval = WAKE_WORD_RETRY_INTERVAL;
+        }
+        match captured {
+            Ok(Some(question)) => {
+                let app_handle = APP_HANDLE.lock().unwrap().clone();
+                if let Some(app_handle) = app_handle {
+                    // a turn started from a hotkey in the meantime, the question can't be asked so the user is told
+                    if !hotkeys::start_keybind_flow(app_handle.clone(), Some(question.clone())) {
+                        println!("Couldn't ask \"{}\", a turn is already running", question);
+                        let _ = app_handle.emit_all(
+                            "busy",
+                            Payload {
+                                message: question,
+                            },
+                        );
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                println!("Error listening for the wake phrase, trying again in {}s: {}", retry_interval.as_secs(), err);
+                // sleeping in short steps so turning wake word listening off doesn't wait for the retry
+                let retry_at = Instant::now() + retry_interval;
+                while Instant::now() < retry_at && !stop_token.is_cancelled() {
+                    thread::sleep(WAKE_WORD_RECHECK_INTERVAL);
+                }
+                retry_interval = (retry_interval * 2).min(WAKE_WORD_MAX_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+// returns true once the wake phrase is heard, or false if listening stopped, was paused or lost the microphone first
+fn wait_for_wake_phrase(
+    audio_input_receiver: &Receiver<Vec<i16>>,
+    sample_rate: SampleRate,
+    wake_phrase: &str,
+    stop_token: &CancellationToken,
+) -> bool {
+    let grammar = [wake_phrase, "[unk]"];
+    let mut recognizer = match Recognizer::new_with_grammar(&get_vosk_model(), sample_rate.0 as f32, &grammar[..]) {
+        Some(recognizer) => recognizer,
+        None => {
+            println!("Couldn't create a recognizer for the wake phrase \"{}\"", wake_phrase);
+            return false;
+        }
+    };
+    let mut voice_activity_detector = VoiceActivityDetector::new(sample_rate.0, WAKE_PHRASE_HANGOVER);
+
+    loop {
+        if stop_token.is_cancelled() || is_paused() {
+            return false;
+        }
+        // the microphone can be taken away while the listener is waiting, it has to stop hearing anything right away.
+        // this is what check_microphone_permission checks, without logging it every time
+        if get_permission_mode(&Permission::Microphone) != PermissionMode::Allow {
+            return false;
+        }
+
+        let data = match audio_input_receiver.recv_timeout(WAKE_WORD_RECHECK_INTERVAL) {
+            Ok(data) => data,
+            Err(RecvTimeoutError::Disconnected) => return false,
+            Err(RecvTimeoutError::Timeout) => continue,
+        };
+
+        for frame in voice_activity_detector.push(&data) {
+            let heard = match frame.activity {
+                VoiceActivity::Silence => false,
+                VoiceActivity::SpeechStarted | VoiceActivity::Speech => {
+                    match recognizer.accept_waveform(&frame.samples) {
+                        DecodingState::Finalized => recognizer
+                            .result()
+                            .single()
+                            .map_or(false, |result| result.text.contains(wake_phrase)),
+                        _ => recognizer.partial_result().partial.contains(wake_phrase),
+                    }
+                }
+                VoiceActivity::SpeechEnded => {
+                    recognizer.accept_waveform(&frame.samples);
+                    recognizer
+                        .final_result()
+                        .single()
+                        .map_or(false, |result| result.text.contains(wake_phrase))
+                }
+            };
+
+            if heard {
+                println!("Heard the wake phrase");
+                return true;
+            }
+        }
+    }
+}
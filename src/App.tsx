@@ -15,6 +15,15 @@ type Payload = {
   message: string;
 };
 
+// a tool magnus wants to use that the user has to approve first
+type ConfirmationRequest = {
+  id: number;
+  tool: string;
+  description: string;
+  arguments: Record<string, unknown>;
+  permissions: string[];
+};
+
 function App() {
   const [text, setText] = useState<string>('')
   const [messages, setMessages] = useState<Message[]>([]);
@@ -23,8 +32,15 @@ function App() {
   const [showSettings, setShowSettings] = useState(false)
   const [showPreview, setShowPreview] = useState(false);
   const [jwt, setJwt] = useState<String | undefined>(undefined);
+  const [backendAvailable, setBackendAvailable] = useState(true);
+  const [confirmations, setConfirmations] = useState<ConfirmationRequest[]>([]);
+  const [speechInputMode, setSpeechInputMode] = useState<string>("silence");
+  // whether the mic button is held for push to talk, a ref so letting go sees it while the permissions are checked
+  const pushToTalkRef = useRef(false);
   const formRef = useRef<HTMLFormElement>(null);
   const textareaRef = useRef<HTMLTextAreaElement>(null);
+  // whether the last message is a response from magnus that is still being streamed in
+  const streamingRef = useRef(false);
 
   const { isLoading, isAuthenticated, error, user, getIdTokenClaims } = useAuth0();
 
@@ -71,7 +87,7 @@ function App() {
     const button = document.getElementById('micButton');
     if (button) {
       await invoke('get_permissions').then((permissions: any) => {
-        if (permissions['Microphone']) {
+        if (permissions['Microphone']?.mode === "allow") {
           if (shouldMic) {
             setShouldMic(false)
             button.style.filter = "invert(100%)"
@@ -89,6 +105,36 @@ function App() {
     }
   }
 
+  // push to talk records while the mic button is held down
+  const handlePushToTalkStart = async (event: React.PointerEvent<HTMLButtonElement>) => {
+    if (pushToTalkRef.current) {
+      return
+    }
+    // set before checking the permissions, so a click that's over by the time they come back doesn't start recording
+    pushToTalkRef.current = true
+    // letting go anywhere ends it, not only over the button
+    event.currentTarget.setPointerCapture(event.pointerId)
+
+    const permissions: any = await invoke('get_permissions')
+    if (!pushToTalkRef.current) {
+      return
+    }
+    if (permissions['Microphone']?.mode !== "allow") {
+      pushToTalkRef.current = false
+      return
+    }
+    invoke('start_push_to_talk')
+    console.log("Collecting Audio")
+  }
+
+  const handlePushToTalkEnd = () => {
+    if (pushToTalkRef.current) {
+      pushToTalkRef.current = false
+      invoke('stop_push_to_talk')
+      console.log("Audio Collecting Turned Off")
+    }
+  }
+
   // only run this flow once so that we down spam the db
   const hasCreated = useRef(false);
   const initialLogin = () => {
@@ -151,6 +197,25 @@ function App() {
           }
         });
 
+        // pieces of magnus' response as they are streamed in
+        await listen<Payload>("magnus-delta", (response) => {
+          if (typeof (response.payload.message) === 'string') {
+            const delta = response.payload.message
+            setLoading(false)
+
+            if (streamingRef.current) {
+              setMessages((prevMessages) => {
+                const lastMessage = prevMessages[prevMessages.length - 1]
+                return [...prevMessages.slice(0, -1), { type: 'magnus', text: lastMessage.text + delta }]
+              })
+            } else {
+              streamingRef.current = true
+              const newMessage: Message = { type: 'magnus', text: delta }
+              setMessages((prevMessages) => [...prevMessages, newMessage])
+            }
+          }
+        })
+
         await listen<Payload>("magnus", (response) => {
           if (typeof (response.payload.message) === 'string') {
 
@@ -159,13 +224,101 @@ function App() {
 
             setLoading(false)
             const newMessage: Message = { type: 'magnus', text: response.payload.message }
-            setMessages((prevMessages) => [...prevMessages, newMessage])
+
+            // the full message replaces the streamed one
+            if (streamingRef.current) {
+              streamingRef.current = false
+              setMessages((prevMessages) => [...prevMessages.slice(0, -1), newMessage])
+            } else {
+              setMessages((prevMessages) => [...prevMessages, newMessage])
+            }
+          }
+        })
+
+        // the turn was cancelled before magnus responded
+        await listen<Payload>("cancelled", () => {
+          canUseInput(true);
+          setLoading(false)
+          streamingRef.current = false
+
+          setShouldMic(true)
+          const button = document.getElementById('micButton');
+          if (button) {
+            button.style.filter = "invert(0%)"
+          }
+        })
+
+        // the mic stopped listening, reason is one of silence, noSpeech, noAudio, released, maxDuration or cancelled
+        await listen<{ reason: string, transcription: string | null, durationMs: number }>("capture-ended", (response) => {
+          console.log(`Capture ended (${response.payload.reason}) after ${response.payload.durationMs}ms`)
+
+          // nothing to answer, so the mic can be used again right away
+          if (response.payload.transcription === null) {
+            setShouldMic(true)
+            const button = document.getElementById('micButton');
+            if (button) {
+              button.style.filter = "invert(0%)"
+            }
+          }
+        })
+
+        // something went wrong during the turn, show the user what happened
+        await listen<Payload>("error", (response) => {
+          canUseInput(true);
+          setLoading(false)
+          streamingRef.current = false
+
+          setShouldMic(true)
+          const button = document.getElementById('micButton');
+          if (button) {
+            button.style.filter = "invert(0%)"
           }
+
+          if (typeof (response.payload.message) === "string") {
+            const errorMessage: Message = { type: 'magnus', text: `*${response.payload.message}*` }
+            setMessages((prevMessages) => [...prevMessages, errorMessage])
+          }
+        })
+
+        // a question heard after the wake phrase couldn't be asked because magnus was already answering one
+        await listen<Payload>("busy", (response) => {
+          if (typeof (response.payload.message) === "string") {
+            const busyMessage: Message = { type: 'magnus', text: `*Still busy, couldn't ask "${response.payload.message}"*` }
+            setMessages((prevMessages) => [...prevMessages, busyMessage])
+          }
+        })
+
+        // whether magnus can currently reach the assistant service
+        await listen<Payload>("backend-status", (response) => {
+          setBackendAvailable(response.payload.message === "available")
+        })
+        invoke("get_is_backend_available").then((isAvailable: any) => {
+          setBackendAvailable(isAvailable as boolean)
+        })
+
+        // magnus wants to use a tool that needs the user's approval
+        await listen<ConfirmationRequest>("confirm-action", (response) => {
+          setConfirmations((prevConfirmations) => [...prevConfirmations, response.payload])
+        })
+
+        // the confirmation was answered, timed out or its turn was cancelled
+        await listen<{ id: number }>("confirm-action-closed", (response) => {
+          setConfirmations((prevConfirmations) => prevConfirmations.filter((confirmation) => confirmation.id !== response.payload.id))
+        })
+
+        // push to talk or stopping when the user goes quiet
+        invoke("get_speech_input_mode").then((mode: any) => {
+          setSpeechInputMode(mode as string)
+        })
+        await listen<any>("settings-changed", (response) => {
+          setSpeechInputMode(response.payload.speechInputMode)
         })
 
         // listen for when magnus takes an action
         await listen<Payload>("action", (response) => {
           if (typeof (response.payload.message) === "string") {
+            // text streamed after the action belongs in a new bubble
+            streamingRef.current = false
             const actionMessage: Message = { type: 'magnus', text: response.payload.message }
             setMessages((prevMessages) => [...prevMessages, actionMessage])
           }
@@ -175,6 +328,33 @@ function App() {
     }
   }, [])
 
+  // escape cancels whatever magnus is currently doing
+  useEffect(() => {
+    const handleEscape = (event: KeyboardEvent) => {
+      if (event.key === 'Escape') {
+        invoke("cancel_conversation")
+      }
+    };
+
+    window.addEventListener('keydown', handleEscape);
+    return () => {
+      window.removeEventListener('keydown', handleEscape);
+    };
+  }, [])
+
+  // approves this use and every other use of the same permissions until magnus is closed
+  function allowForSession(confirmation: ConfirmationRequest) {
+    confirmation.permissions.forEach((permission) => {
+      invoke("grant_permission", { permission: permission, mode: "allow", expiresInSeconds: null, sessionOnly: true })
+    })
+    answerConfirmation(confirmation.id, true)
+  }
+
+  function answerConfirmation(id: number, approved: boolean) {
+    invoke(approved ? "approve_action" : "deny_action", { id: id })
+    setConfirmations((prevConfirmations) => prevConfirmations.filter((confirmation) => confirmation.id !== id))
+  }
+
   const scrollToBottom = () => {
     window.scrollTo({ top: document.body.scrollHeight, behavior: 'smooth' })
   }
@@ -222,11 +402,32 @@ function App() {
     return (
       <div className="container">
         <ChatFrame initialMessages={messages} loading={loading} isSignedIn={isAuthenticated}></ChatFrame>
+        {!backendAvailable && (
+          <div className="backendStatus">Magnus can't reach the assistant service right now, it will try again with your next message.</div>
+        )}
+        {confirmations.length > 0 && (
+          <div className="confirmAction">
+            <div>Magnus wants to use <b>{confirmations[0].tool}</b>: {confirmations[0].description}</div>
+            {Object.keys(confirmations[0].arguments).length > 0 && (
+              <pre>{JSON.stringify(confirmations[0].arguments, null, 2)}</pre>
+            )}
+            <button type="button" onClick={() => answerConfirmation(confirmations[0].id, true)}>Allow</button>
+            <button type="button" onClick={() => allowForSession(confirmations[0])}>Allow until Magnus closes</button>
+            <button type="button" onClick={() => answerConfirmation(confirmations[0].id, false)}>Deny</button>
+          </div>
+        )}
         <form ref={formRef} onSubmit={handleFormSubmit} className="bottomBar">
           <button id="settingsButton" type="button" onClick={() => { setShowSettings(true) }}>
             <img src={SettingsIcon} />
           </button>
-          <button id="micButton" type="button" onClick={handleMicClick}>
+          <button
+            id="micButton"
+            type="button"
+            onClick={speechInputMode === "pushToTalk" ? undefined : handleMicClick}
+            onPointerDown={speechInputMode === "pushToTalk" ? handlePushToTalkStart : undefined}
+            onPointerUp={speechInputMode === "pushToTalk" ? handlePushToTalkEnd : undefined}
+            onPointerCancel={speechInputMode === "pushToTalk" ? handlePushToTalkEnd : undefined}
+          >
             <img src={MicIcon} />
           </button>
           <textarea ref={textareaRef} id="magnus-textbox" value={text} onChange={event => { setText(event.target.value) }} onKeyDown={handleKeyDown} />
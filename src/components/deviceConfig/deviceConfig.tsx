@@ -0,0 +1,91 @@
+import { useEffect, useState } from 'react'
+import { invoke } from "@tauri-apps/api/tauri"
+import './styles.css'
+
+interface Props {
+  deviceName: string
+  direction: "input" | "output"
+}
+
+// empty fields use the device's default
+interface PreferredConfig {
+  sampleRate?: number
+  channels?: number
+  bufferSize?: number
+}
+
+interface SupportedConfig {
+  channels: number
+  minSampleRate: number
+  maxSampleRate: number
+  sampleFormat: string
+}
+
+interface DeviceConfigResponse {
+  default: { channels: number, sampleRate: number, sampleFormat: string }
+  supported: SupportedConfig[]
+  preferred: PreferredConfig
+}
+
+// lets the user pick the sample rate, channels and buffer size a device is opened with
+const DeviceConfig: React.FC<Props> = ({ deviceName, direction }) => {
+  const [deviceConfig, setDeviceConfig] = useState<DeviceConfigResponse | null>(null)
+  const [preferred, setPreferred] = useState<PreferredConfig>({})
+  const [message, setMessage] = useState<string>("")
+
+  useEffect(() => {
+    setMessage("")
+    invoke("get_audio_device_config", { deviceName: deviceName, direction: direction })
+      .then((response: any) => {
+        setDeviceConfig(response as DeviceConfigResponse)
+        setPreferred((response as DeviceConfigResponse).preferred)
+      })
+      .catch((err: any) => setMessage(String(err)))
+  }, [deviceName, direction])
+
+  const updateField = (field: keyof PreferredConfig, value: string) => {
+    const number = parseInt(value)
+    setPreferred({ ...preferred, [field]: isNaN(number) ? undefined : number })
+  }
+
+  const save = async () => {
+    await invoke("set_audio_device_config", { deviceName: deviceName, direction: direction, config: preferred })
+      .then(() => setMessage("Saved"))
+      .catch((err: any) => setMessage(String(err)))
+  }
+
+  if (!deviceConfig) {
+    return message ? <div className="device-config-message">{message}</div> : null
+  }
+
+  return (
+    <div className="device-config">
+      <input
+        type="number"
+        value={preferred.sampleRate ?? ""}
+        placeholder={`${deviceConfig.default.sampleRate} Hz`}
+        onChange={(event) => updateField("sampleRate", event.target.value)}
+      />
+      <input
+        type="number"
+        value={preferred.channels ?? ""}
+        placeholder={`${deviceConfig.default.channels} channels`}
+        onChange={(event) => updateField("channels", event.target.value)}
+      />
+      <input
+        type="number"
+        value={preferred.bufferSize ?? ""}
+        placeholder="Default buffer"
+        onChange={(event) => updateField("bufferSize", event.target.value)}
+      />
+      <button type="button" onClick={save}>Save</button>
+      <div className="device-config-message">
+        {message || deviceConfig.supported
+          .map((config) => `${config.channels}ch ${config.minSampleRate}-${config.maxSampleRate}Hz ${config.sampleFormat}`)
+          .join(", ")}
+      </div>
+    </div>
+  )
+}
+
+export default DeviceConfig
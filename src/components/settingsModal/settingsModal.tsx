@@ -1,18 +1,64 @@
 import React, { useEffect, useRef, useState } from 'react'
 import './styles.css'
 import { invoke } from "@tauri-apps/api/tauri"
+import { listen } from '@tauri-apps/api/event'
 import CicularLoading from "../circularLoading/circularLoading"
 import LogoutButton from '../logoutButton/logoutButton';
 import UserIcon from '../userIcon/userIcon';
 import AudioHeader from '../audioHeader/audioHeader';
+import DeviceConfig from '../deviceConfig/deviceConfig';
 
 interface ModalProps {
   show: boolean;
   onClose: () => void;
 }
 
+// "ask" means magnus asks every time a tool needs the permission
+type PermissionMode = "allow" | "deny" | "ask"
+
+// expiresAt is in unix seconds, once it passes magnus goes back to asking
+interface PermissionGrant {
+  mode: PermissionMode;
+  expiresAt?: number;
+}
+
 interface Permissions {
-  [key: string]: boolean;
+  [key: string]: PermissionGrant;
+}
+
+const ONE_HOUR_SECONDS = 60 * 60
+
+// permissions that are only used by tools, so they can be confirmed each time they're used
+const ASKABLE_PERMISSIONS = ["Clipboard", "Location", "Screenshot"]
+
+// each binding maps a shortcut like "Alt+M" to the action it runs
+interface Hotkeys {
+  bindings: { [shortcut: string]: string };
+  actions: string[];
+  problems: string[];
+}
+
+const HOTKEY_ACTION_LABELS: { [action: string]: string } = {
+  startVoiceTurn: "Start a voice question",
+  cancel: "Cancel",
+  repeatLastAnswer: "Repeat the last answer",
+  toggleMuteTts: "Mute or unmute speech",
+  pasteClipboardAsQuestion: "Ask what's on the clipboard",
+}
+
+// what the wake word listener is doing, as sent in "listening-state" events
+const LISTENING_STATE_LABELS: { [state: string]: string } = {
+  off: "Off",
+  waitingForWakePhrase: "Listening for the wake phrase",
+  listening: "Listening for a question",
+  paused: "Paused while Magnus answers",
+  notAllowed: "Needs the Microphone permission",
+}
+
+interface WakeWord {
+  enabled: boolean;
+  phrase: string;
+  state: string;
 }
 
 interface AudioDeviceSelection {
@@ -28,6 +74,11 @@ const SettingsModal: React.FC<ModalProps> = ({ show, onClose }) => {
   const [audioOutputDeviceSelection, setAudioOutputDeviceSelection] = useState<AudioDeviceSelection>({ devices: [], selected: "" })
   const [inputDeviceSelected, setInputDeviceSelected] = useState<String>("")
   const [outputDeviceSelected, setOutputDeviceSelected] = useState<String>("")
+  const [speechInputMode, setSpeechInputMode] = useState<string>("silence")
+  const [wakeWord, setWakeWord] = useState<WakeWord>({ enabled: false, phrase: "", state: "off" })
+  const [hotkeys, setHotkeys] = useState<Hotkeys>({ bindings: {}, actions: [], problems: [] })
+  // bindings as [shortcut, action] pairs so a shortcut can be edited without losing its place
+  const [hotkeyRows, setHotkeyRows] = useState<[string, string][]>([])
 
   function refreshAudioDevices() {
     // refresh audio input and ouput devices every 2 seconds
@@ -56,13 +107,92 @@ const SettingsModal: React.FC<ModalProps> = ({ show, onClose }) => {
       })
 
       refreshAudioDevices()
+      refreshHotkeys()
+
+      invoke("get_speech_input_mode").then((mode: any) => {
+        setSpeechInputMode(mode as string)
+      })
+
+      invoke("get_wake_word").then((wakeWord: any) => {
+        setWakeWord(wakeWord as WakeWord)
+      })
+    }
+  }, [])
+
+  const saveWakeWord = async (enabled: boolean, phrase: string) => {
+    await invoke("set_wake_word", { enabled: enabled, phrase: phrase })
+      .catch((err: any) => console.log(err))
+  }
+
+  const handleSpeechInputMode = async (event: React.ChangeEvent<HTMLSelectElement>) => {
+    setSpeechInputMode(event.target.value)
+    await invoke("set_speech_input_mode", { mode: event.target.value })
+  }
+
+  function refreshHotkeys() {
+    invoke("get_hotkeys").then((hotkeys: any) => {
+      setHotkeys(hotkeys as Hotkeys)
+      setHotkeyRows(Object.entries((hotkeys as Hotkeys).bindings))
+    })
+  }
+
+  const saveHotkeys = async () => {
+    const bindings = Object.fromEntries(hotkeyRows.filter(([shortcut]) => shortcut.trim() !== ""))
+    await invoke("update_hotkeys", { bindings: bindings })
+      .then(() => refreshHotkeys())
+      .catch((problems: any) => setHotkeys({ ...hotkeys, problems: String(problems).split("\n") }))
+  }
+
+  const updateHotkeyRow = (index: number, shortcut: string, action: string) => {
+    setHotkeyRows(hotkeyRows.map((row, i) => i === index ? [shortcut, action] : row))
+  }
+
+  // keep in sync with changes made anywhere else, e.g. a confirmation or another window
+  useEffect(() => {
+    const unlistenPermissions = listen<Permissions>("permissions-changed", (event) => {
+      // the modal saves its own changes, so only take ones that are actually different to avoid saving them again
+      setPermissions((prevPermissions) =>
+        JSON.stringify(prevPermissions) === JSON.stringify(event.payload) ? prevPermissions : event.payload
+      )
+    })
+
+    const unlistenSettings = listen<any>("settings-changed", (event) => {
+      if (event.payload.audioInputDeviceSelection) {
+        setInputDeviceSelected(event.payload.audioInputDeviceSelection)
+      }
+      if (event.payload.audioOutputDeviceSelection) {
+        setOutputDeviceSelected(event.payload.audioOutputDeviceSelection)
+      }
+    })
+
+    const unlistenListeningState = listen<{ message: string }>("listening-state", (event) => {
+      setWakeWord((prevWakeWord) => ({ ...prevWakeWord, state: event.payload.message }))
+    })
+
+    return () => {
+      unlistenPermissions.then((unlisten) => unlisten())
+      unlistenSettings.then((unlisten) => unlisten())
+      unlistenListeningState.then((unlisten) => unlisten())
     }
   }, [])
 
   const handleToggle = (event: React.ChangeEvent<HTMLInputElement>) => {
-    setPermissions({ ...permissions, [event.target.name]: event.target.checked });
+    const grant: PermissionGrant = { mode: event.target.checked ? "allow" : "deny" }
+    setPermissions({ ...permissions, [event.target.name]: grant });
+  };
+
+  const handlePermissionSelection = (event: React.ChangeEvent<HTMLSelectElement>) => {
+    const grant: PermissionGrant = event.target.value === "allowHour"
+      ? { mode: "allow", expiresAt: Math.floor(Date.now() / 1000) + ONE_HOUR_SECONDS }
+      : { mode: event.target.value as PermissionMode }
+    setPermissions({ ...permissions, [event.target.name]: grant });
   };
 
+  // shows grants that run out as their own option so the select doesn't claim they're permanent
+  function getSelectedOption(grant: PermissionGrant) {
+    return grant.expiresAt ? "allowUntil" : grant.mode
+  }
+
   useEffect(() => {
     async function updatePermissions() {
       if (Object.keys(permissions).length > 0) {
@@ -119,18 +249,96 @@ const SettingsModal: React.FC<ModalProps> = ({ show, onClose }) => {
             {Object.entries(permissions).map(([name, value]) => (
               <div key={name} className="permissions">
                 <label className="label" htmlFor={name}>{name}</label>
-                <label className="switch">
-                  <input
-                    name={name}
-                    type="checkbox"
-                    checked={value}
-                    onChange={handleToggle}
-                  />
-                  <span className="slider round"></span>
-                </label>
+                {ASKABLE_PERMISSIONS.includes(name) ? (
+                  <select id={name} name={name} value={getSelectedOption(value)} onChange={handlePermissionSelection}>
+                    <option value="allow">Allow</option>
+                    <option value="allowHour">Allow for the next hour</option>
+                    {value.expiresAt && (
+                      <option value="allowUntil" disabled>
+                        Allow until {new Date(value.expiresAt * 1000).toLocaleTimeString()}
+                      </option>
+                    )}
+                    <option value="ask">Ask every time</option>
+                    <option value="deny">Deny</option>
+                  </select>
+                ) : (
+                  <label className="switch">
+                    <input
+                      name={name}
+                      type="checkbox"
+                      checked={value.mode === "allow"}
+                      onChange={handleToggle}
+                    />
+                    <span className="slider round"></span>
+                  </label>
+                )}
               </div>
             ))}
             <hr />
+            Speech Input
+            <hr />
+            <div className="permissions">
+              <label className="label" htmlFor="speechInputMode">Stop listening</label>
+              <select id="speechInputMode" value={speechInputMode} onChange={handleSpeechInputMode}>
+                <option value="silence">When I stop talking</option>
+                <option value="pushToTalk">When I let go of the mic button, or press the hotkey again</option>
+              </select>
+            </div>
+            <div className="permissions">
+              <label className="label" htmlFor="wakeWordEnabled">Wake word</label>
+              <label className="switch">
+                <input
+                  id="wakeWordEnabled"
+                  type="checkbox"
+                  checked={wakeWord.enabled}
+                  disabled={wakeWord.phrase.trim() === ""}
+                  onChange={(event) => {
+                    setWakeWord({ ...wakeWord, enabled: event.target.checked })
+                    saveWakeWord(event.target.checked, wakeWord.phrase)
+                  }}
+                />
+                <span className="slider round"></span>
+              </label>
+            </div>
+            <div className="permissions">
+              <label className="label" htmlFor="wakePhrase">Wake phrase</label>
+              <input
+                id="wakePhrase"
+                type="text"
+                value={wakeWord.phrase}
+                placeholder="hey magnus"
+                onChange={(event) => setWakeWord({ ...wakeWord, phrase: event.target.value })}
+                onBlur={() => saveWakeWord(wakeWord.enabled, wakeWord.phrase)}
+              />
+            </div>
+            <div className="listening-state">{LISTENING_STATE_LABELS[wakeWord.state] ?? wakeWord.state}</div>
+            <hr />
+            Hotkeys
+            <hr />
+            {hotkeyRows.map(([shortcut, action], index) => (
+              <div key={index} className="hotkey">
+                <input
+                  type="text"
+                  value={shortcut}
+                  placeholder="Alt+M"
+                  onChange={(event) => updateHotkeyRow(index, event.target.value, action)}
+                />
+                <select value={action} onChange={(event) => updateHotkeyRow(index, shortcut, event.target.value)}>
+                  {hotkeys.actions.map((hotkeyAction) => (
+                    <option key={hotkeyAction} value={hotkeyAction}>{HOTKEY_ACTION_LABELS[hotkeyAction] ?? hotkeyAction}</option>
+                  ))}
+                </select>
+                <button type="button" onClick={() => setHotkeyRows(hotkeyRows.filter((_, i) => i !== index))}>&times;</button>
+              </div>
+            ))}
+            {hotkeys.problems.map((problem) => (
+              <div key={problem} className="hotkey-problem">{problem}</div>
+            ))}
+            <div className="hotkey-buttons">
+              <button type="button" onClick={() => setHotkeyRows([...hotkeyRows, ["", hotkeys.actions[0] ?? "startVoiceTurn"]])}>Add</button>
+              <button type="button" onClick={saveHotkeys}>Save</button>
+            </div>
+            <hr />
             <AudioHeader onClickRefresh={refreshAudioDevices} />
             <hr />
             {audioInputDeviceSelection.selected != "" ? (
@@ -152,6 +360,7 @@ const SettingsModal: React.FC<ModalProps> = ({ show, onClose }) => {
                     </div>
                   ))}
                 </div>
+                {inputDeviceSelected && <DeviceConfig deviceName={String(inputDeviceSelected)} direction="input" />}
                 <div className="section-title">Output</div>
                 <div className='device-list'>
                   {audioOutputDeviceSelection.devices.map((device, index) => (
@@ -169,6 +378,7 @@ const SettingsModal: React.FC<ModalProps> = ({ show, onClose }) => {
                     </div>
                   ))}
                 </div>
+                {outputDeviceSelected && <DeviceConfig deviceName={String(outputDeviceSelected)} direction="output" />}
               </div>
             ) : (
               <CicularLoading size='small' />